@@ -0,0 +1,133 @@
+use crate::data_source::user::Dealer;
+use crate::data_source::{DataSource, Roles};
+use futures::TryStreamExt;
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{doc, Bson, DateTime};
+use mongodb::error::Error;
+use mongodb::Collection;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub enum AuditAction {
+    CreateGameday,
+    SetGamedayStatus,
+    CreateTemplate,
+    CreatePins,
+    RevokePins,
+    ReissuePin,
+    CreateGame,
+    PatchGame,
+    DeleteGame,
+    SetCredits,
+    AdjustCredits,
+    SettleRound,
+    ResolveBets,
+    VoidBets,
+    RegisterDealer,
+    ChangePassword,
+    ResetPassword,
+    SetDealerStatus,
+    DeleteDealer,
+    RevokeSessions,
+    ClearLockout,
+}
+
+impl Display for AuditAction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Filters for querying the audit log, all of them are optional.
+pub struct AuditFilter {
+    pub actor_id: Option<ObjectId>,
+    pub target: Option<String>,
+    pub action: Option<AuditAction>,
+    pub from: Option<DateTime>,
+    pub to: Option<DateTime>,
+    pub limit: i64,
+}
+
+/// A single staff action. Entries are only ever inserted, never updated or deleted.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AuditEntry {
+    pub(crate) _id: ObjectId,
+    pub(crate) actor_id: ObjectId,
+    pub(crate) actor_role: Roles,
+    pub(crate) action: AuditAction,
+    pub(crate) target: Option<String>,
+    pub(crate) before: Option<Bson>,
+    pub(crate) after: Option<Bson>,
+    pub(crate) created_at: DateTime,
+}
+
+impl AuditEntry {
+    pub fn new(actor: &Dealer, action: AuditAction, target: Option<String>) -> Self {
+        AuditEntry {
+            _id: ObjectId::new(),
+            actor_id: actor._id,
+            actor_role: actor.role,
+            action,
+            target,
+            before: None,
+            after: None,
+            created_at: DateTime::now(),
+        }
+    }
+
+    pub fn with_change(mut self, before: Option<Bson>, after: Option<Bson>) -> Self {
+        self.before = before;
+        self.after = after;
+        self
+    }
+
+    pub async fn insert(&self, data_source: DataSource) -> Result<ObjectId, Error> {
+        let client = data_source.get_new_db_client().await?;
+        let db = client.database(data_source.database_identifier);
+        let collection: Collection<AuditEntry> = db.collection(data_source.collection_identifier);
+
+        collection.insert_one(self).await?;
+
+        Ok(self._id)
+    }
+
+    /// Newest entries first.
+    pub async fn find(filter: AuditFilter, data_source: DataSource) -> Result<Vec<Self>, Error> {
+        let client = data_source.get_new_db_client().await?;
+        let db = client.database(data_source.database_identifier);
+        let collection: Collection<AuditEntry> = db.collection(data_source.collection_identifier);
+
+        let mut query = doc! {};
+        if let Some(actor_id) = filter.actor_id {
+            query.insert("actor_id", actor_id);
+        }
+        if let Some(target) = filter.target {
+            query.insert("target", target);
+        }
+        if let Some(action) = filter.action {
+            query.insert("action", action.to_string());
+        }
+
+        let mut created_at = doc! {};
+        if let Some(from) = filter.from {
+            created_at.insert("$gte", from);
+        }
+        if let Some(to) = filter.to {
+            created_at.insert("$lte", to);
+        }
+        if !created_at.is_empty() {
+            query.insert("created_at", created_at);
+        }
+
+        let res = collection
+            .find(query)
+            .sort(doc! { "created_at": -1 })
+            .limit(filter.limit)
+            .await?
+            .try_collect()
+            .await?;
+
+        Ok(res)
+    }
+}
@@ -0,0 +1,315 @@
+use crate::data_source::game::{Game, Outcome};
+use crate::data_source::transaction::{require_transactions, Transaction, TransactionKind};
+use crate::data_source::{DBUser, DataSource, ACTIVE_USERS, TRANSACTIONS};
+use futures::TryStreamExt;
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{doc, DateTime};
+use mongodb::error::Error;
+use mongodb::options::ReturnDocument;
+use mongodb::{ClientSession, Collection};
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum BetStatus {
+    Open,
+    Won,
+    Lost,
+    Refunded,
+}
+
+impl Display for BetStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// A stake of a player on an outcome of a game. The stake is taken from the player when the bet
+/// is placed and paid back with the multiplier the outcome had at that time if it wins.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Bet {
+    pub(crate) _id: ObjectId,
+    pub(crate) user_id: ObjectId,
+    pub(crate) game_id: ObjectId,
+    pub(crate) outcome: String,
+    /// Unset for bets placed before the multiplier was stored, those are paid by the current odds.
+    #[serde(default)]
+    pub(crate) multiplier: Option<f64>,
+    pub(crate) stake: u64,
+    pub(crate) status: BetStatus,
+    pub(crate) payout: Option<u64>,
+    pub(crate) created_at: DateTime,
+    pub(crate) resolved_at: Option<DateTime>,
+}
+
+impl Bet {
+    /// Holds the stake and opens the bet. The player has to be seated at the game and able to
+    /// afford the stake, otherwise `None` is returned and nothing is changed.
+    pub async fn place(
+        user_id: ObjectId,
+        game_id: ObjectId,
+        outcome: &Outcome,
+        stake: u64,
+        data_source: DataSource,
+    ) -> Result<Option<Self>, Error> {
+        require_transactions()?;
+
+        let client = data_source.get_new_db_client().await?;
+        let mut session = client.start_session().await?;
+        session.start_transaction().await?;
+
+        let bet = Bet {
+            _id: ObjectId::new(),
+            user_id,
+            game_id,
+            outcome: outcome.name.clone(),
+            multiplier: Some(outcome.multiplier),
+            stake,
+            status: BetStatus::Open,
+            payout: None,
+            created_at: DateTime::now(),
+            resolved_at: None,
+        };
+
+        match bet.place_in_session(&data_source, &mut session).await {
+            Ok(true) => {
+                session.commit_transaction().await?;
+                Ok(Some(bet))
+            }
+            Ok(false) => {
+                session.abort_transaction().await?;
+                Ok(None)
+            }
+            Err(e) => {
+                session.abort_transaction().await?;
+                Err(e)
+            }
+        }
+    }
+
+    async fn place_in_session(
+        &self,
+        data_source: &DataSource,
+        session: &mut ClientSession,
+    ) -> Result<bool, Error> {
+        let stake = self.stake as i64;
+
+        let filter = doc! {
+            "_id": self.user_id,
+            "active_game": self.game_id,
+            "credits": { "$gte": stake },
+        };
+        let res = users(session)
+            .find_one_and_update(filter, doc! { "$inc": { "credits": -stake } })
+            .return_document(ReturnDocument::After)
+            .session(&mut *session)
+            .await?;
+
+        let balance = match res {
+            Some(u) => u.credits.unwrap_or(0),
+            None => return Ok(false),
+        };
+
+        bets(session, data_source)
+            .insert_one(self)
+            .session(&mut *session)
+            .await?;
+
+        Transaction::new(
+            self.user_id,
+            TransactionKind::BetStake,
+            -stake,
+            balance as i64,
+        )
+        .with_game(Some(self.game_id))
+        .insert_with_session(session, TRANSACTIONS)
+        .await?;
+
+        Ok(true)
+    }
+
+    /// Number of open bets at any of the games.
+    pub async fn count_open(game_ids: &[ObjectId], data_source: DataSource) -> Result<u64, Error> {
+        let client = data_source.get_new_db_client().await?;
+        let db = client.database(data_source.database_identifier);
+        let collection: Collection<Bet> = db.collection(data_source.collection_identifier);
+
+        let filter = doc! {
+            "game_id": { "$in": game_ids },
+            "status": BetStatus::Open.to_string(),
+        };
+        collection.count_documents(filter).await
+    }
+
+    /// Open bets at the table, oldest first.
+    pub async fn get_open(game_id: &ObjectId, data_source: DataSource) -> Result<Vec<Self>, Error> {
+        let client = data_source.get_new_db_client().await?;
+        let db = client.database(data_source.database_identifier);
+        let collection: Collection<Bet> = db.collection(data_source.collection_identifier);
+
+        let filter = doc! { "game_id": game_id, "status": BetStatus::Open.to_string() };
+        let res = collection
+            .find(filter)
+            .sort(doc! { "created_at": 1 })
+            .await?
+            .try_collect()
+            .await?;
+
+        Ok(res)
+    }
+
+    /// Closes all open bets at the table. Bets on the winning outcome are paid out with its
+    /// multiplier, all others are lost. With `winning` unset the round is void and every
+    /// stake is refunded. Returns the closed bets.
+    pub async fn close_round(
+        game: &Game,
+        winning: Option<&str>,
+        actor_id: ObjectId,
+        data_source: DataSource,
+    ) -> Result<Vec<Self>, Error> {
+        require_transactions()?;
+
+        let client = data_source.get_new_db_client().await?;
+        let mut session = client.start_session().await?;
+        session.start_transaction().await?;
+
+        let res =
+            Self::close_round_in_session(game, winning, actor_id, &data_source, &mut session).await;
+
+        match res {
+            Ok(bets) => {
+                session.commit_transaction().await?;
+                Ok(bets)
+            }
+            Err(e) => {
+                session.abort_transaction().await?;
+                Err(e)
+            }
+        }
+    }
+
+    pub(crate) async fn close_round_in_session(
+        game: &Game,
+        winning: Option<&str>,
+        actor_id: ObjectId,
+        data_source: &DataSource,
+        session: &mut ClientSession,
+    ) -> Result<Vec<Self>, Error> {
+        let filter = doc! { "game_id": game._id, "status": BetStatus::Open.to_string() };
+        let mut cursor = bets(session, data_source)
+            .find(filter)
+            .sort(doc! { "created_at": 1 })
+            .session(&mut *session)
+            .await?;
+
+        let mut open = vec![];
+        while let Some(bet) = cursor.next(&mut *session).await {
+            open.push(bet?);
+        }
+
+        let now = DateTime::now();
+        let mut closed = vec![];
+
+        for mut bet in open {
+            let (status, payout, kind) = match winning {
+                None => (BetStatus::Refunded, bet.stake, TransactionKind::BetRefund),
+                Some(w) if w == bet.outcome => {
+                    let odds = match bet.multiplier {
+                        Some(multiplier) => Some(Outcome {
+                            name: bet.outcome.clone(),
+                            multiplier,
+                        }),
+                        None => game.payouts.outcome(w).cloned(),
+                    };
+                    let payout = match odds {
+                        Some(o) => o.payout(bet.stake).ok_or_else(|| payout_too_large(&bet))?,
+                        None => 0,
+                    };
+                    (BetStatus::Won, payout, TransactionKind::BetPayout)
+                }
+                Some(_) => (BetStatus::Lost, 0, TransactionKind::BetPayout),
+            };
+
+            let amount = i64::try_from(payout).map_err(|_| payout_too_large(&bet))?;
+
+            if payout > 0 {
+                let res = users(session)
+                    .find_one_and_update(
+                        doc! { "_id": bet.user_id },
+                        doc! { "$inc": { "credits": amount } },
+                    )
+                    .return_document(ReturnDocument::After)
+                    .session(&mut *session)
+                    .await?;
+
+                if let Some(user) = res {
+                    let balance = user.credits.unwrap_or(0) as i64;
+                    Transaction::new(bet.user_id, kind, amount, balance)
+                        .with_game(Some(game._id))
+                        .with_actor(Some(actor_id))
+                        .insert_with_session(session, TRANSACTIONS)
+                        .await?;
+                }
+            }
+
+            let update = doc! {
+                "$set": { "status": status.to_string(), "payout": amount, "resolved_at": now },
+            };
+            bets(session, data_source)
+                .update_one(doc! { "_id": bet._id }, update)
+                .session(&mut *session)
+                .await?;
+
+            bet.status = status;
+            bet.payout = Some(payout);
+            bet.resolved_at = Some(now);
+            closed.push(bet);
+        }
+
+        Ok(closed)
+    }
+
+    pub fn get_json_value(&self) -> serde_json::Value {
+        serde_json::json!({
+            "_id": self._id.to_string(),
+            "user_id": self.user_id.to_string(),
+            "game_id": self.game_id.to_string(),
+            "outcome": self.outcome,
+            "multiplier": self.multiplier,
+            "stake": self.stake,
+            "status": self.status.to_string(),
+            "payout": self.payout,
+            "created_at": self.created_at.try_to_rfc3339_string().unwrap_or_default(),
+        })
+    }
+}
+
+fn payout_too_large(bet: &Bet) -> Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("Payout of bet {} does not fit in a balance", bet._id),
+    )
+    .into()
+}
+
+/// Whether resolving failed because a payout does not fit in a balance, the round is left open.
+pub fn is_payout_too_large(e: &Error) -> bool {
+    match e.kind.as_ref() {
+        mongodb::error::ErrorKind::Io(io) => io.kind() == std::io::ErrorKind::InvalidData,
+        _ => false,
+    }
+}
+
+fn users(session: &ClientSession) -> Collection<DBUser> {
+    session
+        .client()
+        .database(ACTIVE_USERS.database_identifier)
+        .collection(ACTIVE_USERS.collection_identifier)
+}
+
+fn bets(session: &ClientSession, data_source: &DataSource) -> Collection<Bet> {
+    session
+        .client()
+        .database(data_source.database_identifier)
+        .collection(data_source.collection_identifier)
+}
@@ -1,13 +1,112 @@
 use crate::data_source;
-use crate::data_source::user::Player;
-use crate::data_source::{DBUser, DataSource};
+use crate::data_source::bet::Bet;
+use crate::data_source::transaction::{require_transactions, Transaction, TransactionKind};
+use crate::data_source::user::{update_and_record, Player};
+use crate::data_source::{DBUser, DataSource, TRANSACTIONS};
 use futures::stream::TryStreamExt;
+use log::error;
 use mongodb::bson::doc;
 use mongodb::bson::oid::ObjectId;
 use mongodb::error::Error;
-use mongodb::Collection;
+use mongodb::options::ReturnDocument;
+use mongodb::{ClientSession, Collection};
 use serde::{Deserialize, Serialize};
 
+/// Something a player can bet on. A winning bet returns `stake * multiplier`, the stake included.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Outcome {
+    pub(crate) name: String,
+    pub(crate) multiplier: f64,
+}
+
+/// Highest multiplier an outcome may pay, keeps payouts of sane stakes within a balance.
+pub const MAX_MULTIPLIER: f64 = 1000.0;
+
+impl Outcome {
+    /// Credits returned for a winning stake on this outcome, `None` if they do not fit in a balance.
+    pub fn payout(&self, stake: u64) -> Option<u64> {
+        let payout = (stake as f64 * self.multiplier).floor();
+        if !payout.is_finite() || payout < 0.0 || payout >= i64::MAX as f64 {
+            return None;
+        }
+        Some(payout as u64)
+    }
+}
+
+/// How bets at a game are paid out. `house_edge` is informational only, it is not applied to payouts.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PayoutSchema {
+    #[serde(default)]
+    pub(crate) outcomes: Vec<Outcome>,
+    pub(crate) min_stake: Option<u64>,
+    pub(crate) max_stake: Option<u64>,
+    pub(crate) house_edge: Option<f64>,
+}
+
+impl PayoutSchema {
+    pub fn validate(&self) -> Result<(), String> {
+        for (i, outcome) in self.outcomes.iter().enumerate() {
+            if outcome.name.trim().is_empty() {
+                return Err("Outcome names can not be empty".to_string());
+            }
+            if self.outcomes[..i].iter().any(|o| o.name == outcome.name) {
+                return Err(format!("Outcome {} is listed twice", outcome.name));
+            }
+            if !outcome.multiplier.is_finite() || outcome.multiplier <= 0.0 {
+                return Err(format!("Multiplier of {} has to be positive", outcome.name));
+            }
+            if outcome.multiplier > MAX_MULTIPLIER {
+                return Err(format!(
+                    "Multiplier of {} can not be above {}",
+                    outcome.name, MAX_MULTIPLIER
+                ));
+            }
+        }
+
+        if self.min_stake == Some(0) {
+            return Err("Minimum stake has to be positive".to_string());
+        }
+        if let (Some(min), Some(max)) = (self.min_stake, self.max_stake) {
+            if min > max {
+                return Err("Minimum stake is above the maximum stake".to_string());
+            }
+        }
+        if self
+            .max_stake
+            .is_some_and(|max| i64::try_from(max).is_err())
+        {
+            return Err("Maximum stake is too large".to_string());
+        }
+
+        if let Some(edge) = self.house_edge {
+            if !(0.0..1.0).contains(&edge) {
+                return Err("House edge has to be between 0 and 1".to_string());
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn outcome(&self, name: &str) -> Option<&Outcome> {
+        self.outcomes.iter().find(|o| o.name == name)
+    }
+
+    /// Checks the stake against the limits of the game.
+    pub fn allows_stake(&self, stake: u64) -> bool {
+        stake > 0
+            && self.min_stake.is_none_or(|min| stake >= min)
+            && self.max_stake.is_none_or(|max| stake <= max)
+    }
+}
+
+/// Outcome of settling a round at a table.
+pub enum Settlement {
+    /// New balance of every player that took part.
+    Settled(Vec<(ObjectId, u64)>),
+    /// The player left the table or can not cover the loss, nothing was applied.
+    Rejected(ObjectId),
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Game {
     pub(crate) _id: ObjectId,
@@ -15,6 +114,10 @@ pub struct Game {
     pub(crate) join_fee: u32,
     pub(crate) name: String,
     pub(crate) icon_id: String,
+    #[serde(flatten)]
+    pub(crate) payouts: PayoutSchema,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) gameday_id: Option<ObjectId>,
 }
 
 impl Game {
@@ -23,6 +126,8 @@ impl Game {
         name: String,
         description: String,
         icon_id: String,
+        payouts: PayoutSchema,
+        gameday_id: Option<ObjectId>,
         data_source: DataSource,
     ) -> Result<Self, Error> {
         let client = data_source.get_new_db_client().await?;
@@ -35,6 +140,8 @@ impl Game {
             join_fee: initial_costs,
             name,
             icon_id,
+            payouts,
+            gameday_id,
         };
 
         let res = collection.insert_one(&insert_doc).await;
@@ -45,6 +152,52 @@ impl Game {
         }
     }
 
+    /// Creates all games for the gameday at once. If that fails, games that were already
+    /// inserted are removed again, so either all of them exist or none.
+    pub async fn new_many(
+        games: Vec<data_source::Game>,
+        gameday_id: Option<ObjectId>,
+        data_source: DataSource,
+    ) -> Result<Vec<Self>, Error> {
+        let mut insert_docs = vec![];
+        for game in games {
+            let join_fee = u32::try_from(game.join_fee)
+                .map_err(|_| Error::from(std::io::ErrorKind::InvalidData))?;
+
+            insert_docs.push(Game {
+                _id: ObjectId::new(),
+                description: game.description,
+                join_fee,
+                name: game.name,
+                icon_id: game.icon_id,
+                payouts: game.payouts,
+                gameday_id,
+            });
+        }
+
+        if insert_docs.is_empty() {
+            return Ok(insert_docs);
+        }
+
+        let client = data_source.get_new_db_client().await?;
+        let db = client.database(data_source.database_identifier);
+        let collection = db.collection::<Game>(data_source.collection_identifier);
+
+        if let Err(e) = collection.insert_many(&insert_docs).await {
+            let ids: Vec<ObjectId> = insert_docs.iter().map(|g| g._id).collect();
+            if let Err(cleanup_error) = collection.delete_many(doc! { "_id": { "$in": ids } }).await
+            {
+                error!(
+                    "Could not remove partially created games: {}",
+                    cleanup_error
+                );
+            }
+            return Err(e);
+        }
+
+        Ok(insert_docs)
+    }
+
     pub async fn get(id: &ObjectId, data_source: DataSource) -> Result<Option<Self>, Error> {
         let client = data_source.get_new_db_client().await?;
         let db = client.database(data_source.database_identifier);
@@ -56,6 +209,21 @@ impl Game {
         Ok(res)
     }
 
+    pub fn get_json_value(&self) -> serde_json::Value {
+        serde_json::json!({
+            "_id": self._id.to_string(),
+            "join_fee": self.join_fee,
+            "name": self.name,
+            "icon_id": self.icon_id,
+            "description": self.description,
+            "outcomes": self.payouts.outcomes,
+            "min_stake": self.payouts.min_stake,
+            "max_stake": self.payouts.max_stake,
+            "house_edge": self.payouts.house_edge,
+            "gameday_id": self.gameday_id.map(|g| g.to_string()),
+        })
+    }
+
     pub async fn get_players(
         game_id: &ObjectId,
         player_data_source: DataSource,
@@ -75,13 +243,218 @@ impl Game {
         Ok(players)
     }
 
-    pub async fn get_all(game_data_source: DataSource) -> Result<Vec<Self>, Error> {
+    /// Deletes the game after voiding its open bets and letting every seated player leave,
+    /// refunding the join fee if `refund` is set. All of it happens in one transaction where
+    /// supported. Returns how many bets were voided and how many players were seated.
+    pub async fn remove(
+        &self,
+        refund: bool,
+        actor_id: ObjectId,
+        bet_data_source: DataSource,
+        player_data_source: DataSource,
+        game_data_source: DataSource,
+    ) -> Result<(usize, u64), Error> {
+        if !data_source::transactions_supported() {
+            return self
+                .remove_without_transaction(
+                    refund,
+                    actor_id,
+                    bet_data_source,
+                    player_data_source,
+                    game_data_source,
+                )
+                .await;
+        }
+
+        let client = game_data_source.get_new_db_client().await?;
+        let mut session = client.start_session().await?;
+        session.start_transaction().await?;
+
+        let res = self
+            .remove_in_session(
+                refund,
+                actor_id,
+                bet_data_source,
+                player_data_source,
+                game_data_source,
+                &mut session,
+            )
+            .await;
+
+        match res {
+            Ok(counts) => {
+                session.commit_transaction().await?;
+                Ok(counts)
+            }
+            Err(e) => {
+                session.abort_transaction().await?;
+                Err(e)
+            }
+        }
+    }
+
+    async fn remove_in_session(
+        &self,
+        refund: bool,
+        actor_id: ObjectId,
+        bet_data_source: DataSource,
+        player_data_source: DataSource,
+        game_data_source: DataSource,
+        session: &mut ClientSession,
+    ) -> Result<(usize, u64), Error> {
+        let voided =
+            Bet::close_round_in_session(self, None, actor_id, &bet_data_source, session).await?;
+
+        let unseated = self
+            .unseat_players_in_session(refund, actor_id, player_data_source, session)
+            .await?;
+
+        let collection: Collection<Game> = session
+            .client()
+            .database(game_data_source.database_identifier)
+            .collection(game_data_source.collection_identifier);
+        collection
+            .delete_one(doc! { "_id": self._id })
+            .session(&mut *session)
+            .await?;
+
+        Ok((voided.len(), unseated))
+    }
+
+    /// Undo fallback described at [`require_transactions`]. The game is deleted first, so nobody
+    /// can join it while the players are unseated.
+    async fn remove_without_transaction(
+        &self,
+        refund: bool,
+        actor_id: ObjectId,
+        bet_data_source: DataSource,
+        player_data_source: DataSource,
+        game_data_source: DataSource,
+    ) -> Result<(usize, u64), Error> {
+        if !Bet::get_open(&self._id, bet_data_source).await?.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "Open bets can only be voided with transactions",
+            )
+            .into());
+        }
+
+        Self::delete(&self._id, game_data_source).await?;
+
+        let client = player_data_source.get_new_db_client().await?;
+        let collection: Collection<DBUser> = client
+            .database(player_data_source.database_identifier)
+            .collection(player_data_source.collection_identifier);
+        let seated: Vec<DBUser> = collection
+            .find(doc! { "active_game": self._id })
+            .await?
+            .try_collect()
+            .await?;
+
+        let fee = if refund { i64::from(self.join_fee) } else { 0 };
+        for player in &seated {
+            let leave = doc! {
+                "$set": { "active_game": null },
+                "$unset": { "joined_at": "" },
+                "$inc": { "credits": fee },
+            };
+
+            update_and_record(
+                doc! { "_id": player._id, "active_game": self._id },
+                leave,
+                |before| {
+                    if fee == 0 {
+                        return None;
+                    }
+
+                    let balance = before.credits.unwrap_or(0) as i64 + fee;
+                    let transaction =
+                        Transaction::new(before._id, TransactionKind::FeeRefund, fee, balance)
+                            .with_game(Some(self._id))
+                            .with_actor(Some(actor_id));
+                    let undo = doc! {
+                        "$set": { "active_game": self._id, "joined_at": before.joined_at },
+                        "$inc": { "credits": -fee },
+                    };
+                    Some((transaction, undo))
+                },
+                &player_data_source,
+            )
+            .await?;
+        }
+
+        Ok((0, seated.len() as u64))
+    }
+
+    async fn unseat_players_in_session(
+        &self,
+        refund: bool,
+        actor_id: ObjectId,
+        player_data_source: DataSource,
+        session: &mut ClientSession,
+    ) -> Result<u64, Error> {
+        let collection: Collection<DBUser> = session
+            .client()
+            .database(player_data_source.database_identifier)
+            .collection(player_data_source.collection_identifier);
+
+        let fee = if refund { i64::from(self.join_fee) } else { 0 };
+        let leave = doc! {
+            "$set": { "active_game": null },
+            "$unset": { "joined_at": "" },
+            "$inc": { "credits": fee },
+        };
+
+        let mut cursor = collection
+            .find(doc! { "active_game": self._id })
+            .session(&mut *session)
+            .await?;
+        let mut seated = vec![];
+        while let Some(user) = cursor.next(&mut *session).await {
+            seated.push(user?._id);
+        }
+
+        for user_id in &seated {
+            let res = collection
+                .find_one_and_update(
+                    doc! { "_id": user_id, "active_game": self._id },
+                    leave.clone(),
+                )
+                .return_document(ReturnDocument::After)
+                .session(&mut *session)
+                .await?;
+
+            if let (Some(user), true) = (res, fee > 0) {
+                Transaction::new(
+                    *user_id,
+                    TransactionKind::FeeRefund,
+                    fee,
+                    user.credits.unwrap_or(0) as i64,
+                )
+                .with_game(Some(self._id))
+                .with_actor(Some(actor_id))
+                .insert_with_session(session, TRANSACTIONS)
+                .await?;
+            }
+        }
+
+        Ok(seated.len() as u64)
+    }
+
+    /// All games, or only those of the gameday if one is given.
+    pub async fn get_all(
+        gameday_id: Option<ObjectId>,
+        game_data_source: DataSource,
+    ) -> Result<Vec<Self>, Error> {
         let client = game_data_source.get_new_db_client().await?;
         let db = client.database(game_data_source.database_identifier);
         let collection: Collection<Game> =
             db.collection::<Game>(game_data_source.collection_identifier);
 
-        let filter = doc! {};
+        let mut filter = doc! {};
+        if let Some(gameday_id) = gameday_id {
+            filter.insert("gameday_id", gameday_id);
+        }
 
         let res = collection.find(filter).await?;
         let res: Vec<Game> = res.try_collect().await?;
@@ -125,4 +498,198 @@ impl Game {
             Ok(None)
         }
     }
+
+    /// Applies the result of a round to all players at the table in one transaction.
+    /// `results` holds the signed credit change per player; either all of them are applied or none.
+    pub async fn settle(
+        game_id: &ObjectId,
+        results: &[(ObjectId, i64)],
+        reason: &str,
+        actor_id: ObjectId,
+        player_data_source: DataSource,
+    ) -> Result<Settlement, Error> {
+        require_transactions()?;
+
+        let client = player_data_source.get_new_db_client().await?;
+        let mut session = client.start_session().await?;
+        session.start_transaction().await?;
+
+        let res = Self::settle_in_session(
+            game_id,
+            results,
+            reason,
+            actor_id,
+            player_data_source,
+            &mut session,
+        )
+        .await;
+
+        match res {
+            Ok(Settlement::Settled(balances)) => {
+                session.commit_transaction().await?;
+                Ok(Settlement::Settled(balances))
+            }
+            Ok(rejected) => {
+                session.abort_transaction().await?;
+                Ok(rejected)
+            }
+            Err(e) => {
+                session.abort_transaction().await?;
+                Err(e)
+            }
+        }
+    }
+
+    async fn settle_in_session(
+        game_id: &ObjectId,
+        results: &[(ObjectId, i64)],
+        reason: &str,
+        actor_id: ObjectId,
+        player_data_source: DataSource,
+        session: &mut ClientSession,
+    ) -> Result<Settlement, Error> {
+        let collection: Collection<DBUser> = session
+            .client()
+            .database(player_data_source.database_identifier)
+            .collection(player_data_source.collection_identifier);
+
+        let mut balances = vec![];
+
+        for (user_id, amount) in results {
+            let mut filter = doc! { "_id": user_id, "active_game": game_id };
+            if *amount < 0 {
+                filter.insert("credits", doc! { "$gte": -amount });
+            }
+
+            let res = collection
+                .find_one_and_update(filter, doc! { "$inc": { "credits": amount } })
+                .return_document(ReturnDocument::After)
+                .session(&mut *session)
+                .await?;
+
+            let balance = match res {
+                Some(u) => u.credits.unwrap_or(0),
+                None => return Ok(Settlement::Rejected(*user_id)),
+            };
+
+            if *amount != 0 {
+                let kind = if *amount < 0 {
+                    TransactionKind::Charge
+                } else {
+                    TransactionKind::DealerPayout
+                };
+
+                Transaction::new(*user_id, kind, *amount, balance as i64)
+                    .with_game(Some(*game_id))
+                    .with_actor(Some(actor_id))
+                    .with_reason(reason.to_string())
+                    .insert_with_session(session, TRANSACTIONS)
+                    .await?;
+            }
+
+            balances.push((*user_id, balance));
+        }
+
+        Ok(Settlement::Settled(balances))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outcome(name: &str, multiplier: f64) -> Outcome {
+        Outcome {
+            name: name.to_string(),
+            multiplier,
+        }
+    }
+
+    fn schema(outcomes: Vec<Outcome>) -> PayoutSchema {
+        PayoutSchema {
+            outcomes,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn valid_schema_is_accepted() {
+        let schema = PayoutSchema {
+            outcomes: vec![outcome("red", 2.0), outcome("green", 36.0)],
+            min_stake: Some(1),
+            max_stake: Some(100),
+            house_edge: Some(0.027),
+        };
+        assert!(schema.validate().is_ok());
+        assert!(PayoutSchema::default().validate().is_ok());
+    }
+
+    #[test]
+    fn invalid_outcomes_are_rejected() {
+        assert!(schema(vec![outcome(" ", 2.0)]).validate().is_err());
+        assert!(schema(vec![outcome("red", 2.0), outcome("red", 3.0)])
+            .validate()
+            .is_err());
+        assert!(schema(vec![outcome("red", 0.0)]).validate().is_err());
+        assert!(schema(vec![outcome("red", -1.0)]).validate().is_err());
+        assert!(schema(vec![outcome("red", f64::NAN)]).validate().is_err());
+        assert!(schema(vec![outcome("red", f64::INFINITY)])
+            .validate()
+            .is_err());
+        assert!(schema(vec![outcome("red", MAX_MULTIPLIER + 1.0)])
+            .validate()
+            .is_err());
+        assert!(schema(vec![outcome("red", MAX_MULTIPLIER)])
+            .validate()
+            .is_ok());
+    }
+
+    #[test]
+    fn invalid_limits_are_rejected() {
+        let with_limits = |min_stake, max_stake, house_edge| PayoutSchema {
+            min_stake,
+            max_stake,
+            house_edge,
+            ..Default::default()
+        };
+
+        assert!(with_limits(Some(0), None, None).validate().is_err());
+        assert!(with_limits(Some(10), Some(5), None).validate().is_err());
+        assert!(with_limits(None, Some(u64::MAX), None).validate().is_err());
+        assert!(with_limits(None, None, Some(1.0)).validate().is_err());
+        assert!(with_limits(None, None, Some(-0.1)).validate().is_err());
+        assert!(with_limits(Some(5), Some(5), Some(0.0)).validate().is_ok());
+    }
+
+    #[test]
+    fn stakes_are_checked_against_the_limits() {
+        let limited = PayoutSchema {
+            min_stake: Some(5),
+            max_stake: Some(50),
+            ..Default::default()
+        };
+        assert!(!limited.allows_stake(4));
+        assert!(limited.allows_stake(5));
+        assert!(limited.allows_stake(50));
+        assert!(!limited.allows_stake(51));
+
+        let unlimited = PayoutSchema::default();
+        assert!(!unlimited.allows_stake(0));
+        assert!(unlimited.allows_stake(u64::MAX));
+    }
+
+    #[test]
+    fn payout_includes_the_stake_and_rounds_down() {
+        assert_eq!(outcome("red", 2.0).payout(10), Some(20));
+        assert_eq!(outcome("split", 1.5).payout(5), Some(7));
+    }
+
+    #[test]
+    fn payout_beyond_a_balance_is_refused() {
+        let max_stake = i64::MAX as u64;
+        assert_eq!(outcome("red", 2.0).payout(max_stake), None);
+        assert_eq!(outcome("red", MAX_MULTIPLIER).payout(u64::MAX), None);
+        assert_eq!(outcome("red", f64::INFINITY).payout(1), None);
+        assert!(outcome("red", MAX_MULTIPLIER).payout(1_000_000).is_some());
+    }
 }
@@ -1,21 +1,99 @@
 use crate::data_source::game::Game;
-use mongodb::bson::{doc, oid::ObjectId};
+use crate::data_source::DataSource;
+use futures::TryStreamExt;
+use mongodb::bson::{doc, oid::ObjectId, Bson};
 use mongodb::Collection;
 use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
 use std::io::{Error, ErrorKind};
 
+/// Lifecycle of a gameday: draft -> open <-> paused -> closed -> archived.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum GamedayStatus {
+    Draft,
+    /// Gamedays from before the lifecycle was introduced count as open.
+    #[default]
+    Open,
+    Paused,
+    Closed,
+    Archived,
+}
+
+impl Display for GamedayStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let status = match self {
+            GamedayStatus::Draft => "draft",
+            GamedayStatus::Open => "open",
+            GamedayStatus::Paused => "paused",
+            GamedayStatus::Closed => "closed",
+            GamedayStatus::Archived => "archived",
+        };
+        f.write_str(status)
+    }
+}
+
+impl GamedayStatus {
+    pub fn can_transition_to(&self, next: GamedayStatus) -> bool {
+        use GamedayStatus::*;
+
+        matches!(
+            (self, next),
+            (Draft, Open)
+                | (Open, Paused)
+                | (Open, Closed)
+                | (Paused, Open)
+                | (Paused, Closed)
+                | (Closed, Archived)
+        )
+    }
+
+    pub fn allows_registration(&self) -> bool {
+        matches!(self, GamedayStatus::Open | GamedayStatus::Paused)
+    }
+
+    /// Joining games, placing bets and transfers between players.
+    pub fn allows_play(&self) -> bool {
+        matches!(self, GamedayStatus::Open)
+    }
+
+    pub fn allows_credit_changes(&self) -> bool {
+        !matches!(self, GamedayStatus::Closed | GamedayStatus::Archived)
+    }
+
+    pub fn allows_new_games(&self) -> bool {
+        !matches!(self, GamedayStatus::Closed | GamedayStatus::Archived)
+    }
+
+    pub fn allows_new_pins(&self) -> bool {
+        !matches!(self, GamedayStatus::Closed | GamedayStatus::Archived)
+    }
+
+    /// Everything but reading is rejected once a gameday is archived.
+    pub fn allows_changes(&self) -> bool {
+        !matches!(self, GamedayStatus::Archived)
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Gameday {
     pub(crate) initial_player_credits: u64,
     pub(crate) name: String,
     pub(crate) games: Vec<Game>,
     pub(crate) _id: ObjectId,
+    /// Most credits a single player may transfer to others during the gameday, unlimited if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) transfer_cap: Option<u64>,
+    #[serde(default)]
+    pub(crate) status: GamedayStatus,
 }
 
 impl Gameday {
+    #[allow(clippy::new_ret_no_self)]
     pub async fn new(
         initial_player_credits: u64,
         name: String,
+        transfer_cap: Option<u64>,
         collection: &Collection<Gameday>,
     ) -> Result<ObjectId, Error> {
         let id = ObjectId::new();
@@ -25,6 +103,8 @@ impl Gameday {
             name,
             games: vec![],
             _id: id,
+            transfer_cap,
+            status: GamedayStatus::Draft,
         };
 
         let res = collection.insert_one(&insert_doc).await;
@@ -34,5 +114,135 @@ impl Gameday {
             Err(e) => Err(Error::new(ErrorKind::ConnectionRefused, e.to_string())),
         }
     }
+
+    /// Moves the gameday to the next status if the transition is allowed from its current one.
+    /// Returns the previous status, or `None` if the gameday was not found or the transition
+    /// is not possible.
+    pub async fn set_status(
+        _id: &ObjectId,
+        next: GamedayStatus,
+        data_source: DataSource,
+    ) -> Result<Option<GamedayStatus>, mongodb::error::Error> {
+        let client = data_source.get_new_db_client().await?;
+        let db = client.database(data_source.database_identifier);
+        let collection: Collection<Gameday> = db.collection(data_source.collection_identifier);
+
+        let gameday = match collection.find_one(doc! { "_id": _id }).await? {
+            Some(g) => g,
+            None => return Ok(None),
+        };
+
+        if !gameday.status.can_transition_to(next) {
+            return Ok(None);
+        }
+
+        // Matching the current status keeps concurrent transitions from overwriting each other
+        let current = match gameday.status {
+            GamedayStatus::Open => doc! { "$in": [GamedayStatus::Open.to_string(), Bson::Null] },
+            status => doc! { "$eq": status.to_string() },
+        };
+        let res = collection
+            .update_one(
+                doc! { "_id": _id, "status": current },
+                doc! { "$set": { "status": next.to_string() } },
+            )
+            .await?;
+
+        match res.modified_count {
+            1 => Ok(Some(gameday.status)),
+            _ => Ok(None),
+        }
+    }
+
+    pub async fn get_all(data_source: DataSource) -> Result<Vec<Self>, mongodb::error::Error> {
+        let client = data_source.get_new_db_client().await?;
+        let db = client.database(data_source.database_identifier);
+        let collection: Collection<Gameday> = db.collection(data_source.collection_identifier);
+
+        collection.find(doc! {}).await?.try_collect().await
+    }
+
+    pub async fn get(
+        _id: &ObjectId,
+        data_source: DataSource,
+    ) -> Result<Option<Self>, mongodb::error::Error> {
+        let client = data_source.get_new_db_client().await?;
+        let db = client.database(data_source.database_identifier);
+        let collection: Collection<Gameday> = db.collection(data_source.collection_identifier);
+
+        collection.find_one(doc! { "_id": _id }).await
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::GamedayStatus::*;
+    use super::*;
+
+    const ALL: [GamedayStatus; 5] = [Draft, Open, Paused, Closed, Archived];
+
+    #[test]
+    fn allowed_transitions() {
+        let allowed = [
+            (Draft, Open),
+            (Open, Paused),
+            (Open, Closed),
+            (Paused, Open),
+            (Paused, Closed),
+            (Closed, Archived),
+        ];
+
+        for from in ALL {
+            for to in ALL {
+                assert_eq!(
+                    from.can_transition_to(to),
+                    allowed.contains(&(from, to)),
+                    "{} -> {}",
+                    from,
+                    to
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn only_open_gamedays_allow_play() {
+        for status in ALL {
+            assert_eq!(status.allows_play(), status == Open, "{}", status);
+        }
+    }
+
+    #[test]
+    fn registration_while_open_or_paused() {
+        for status in ALL {
+            assert_eq!(
+                status.allows_registration(),
+                matches!(status, Open | Paused),
+                "{}",
+                status
+            );
+        }
+    }
+
+    #[test]
+    fn credits_pins_and_games_frozen_once_closed() {
+        for status in ALL {
+            let frozen = matches!(status, Closed | Archived);
+            assert_eq!(status.allows_credit_changes(), !frozen, "{}", status);
+            assert_eq!(status.allows_new_pins(), !frozen, "{}", status);
+            assert_eq!(status.allows_new_games(), !frozen, "{}", status);
+        }
+    }
+
+    #[test]
+    fn archived_gamedays_reject_changes() {
+        for status in ALL {
+            assert_eq!(status.allows_changes(), status != Archived, "{}", status);
+        }
+    }
+
+    #[test]
+    fn missing_status_counts_as_open() {
+        assert_eq!(GamedayStatus::default(), Open);
+    }
+}
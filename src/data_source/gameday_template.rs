@@ -0,0 +1,137 @@
+use crate::data_source;
+use crate::data_source::game::Game;
+use crate::data_source::gameday::Gameday;
+use crate::data_source::DataSource;
+use futures::TryStreamExt;
+use log::error;
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{doc, DateTime};
+use mongodb::error::Error;
+use mongodb::Collection;
+use serde::{Deserialize, Serialize};
+
+/// A reusable gameday setup: starting credits and the catalog of games.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GamedayTemplate {
+    pub(crate) _id: ObjectId,
+    pub(crate) name: String,
+    pub(crate) initial_player_credits: u64,
+    pub(crate) transfer_cap: Option<u64>,
+    pub(crate) games: Vec<data_source::Game>,
+    pub(crate) created_at: DateTime,
+}
+
+impl GamedayTemplate {
+    /// Saves the gameday and its games as a new template.
+    pub async fn from_gameday(
+        name: String,
+        gameday: &Gameday,
+        games: Vec<Game>,
+        data_source: DataSource,
+    ) -> Result<Self, Error> {
+        let games = games
+            .into_iter()
+            .map(|g| data_source::Game {
+                name: g.name,
+                icon_id: g.icon_id,
+                join_fee: g.join_fee as u64,
+                description: g.description,
+                payouts: g.payouts,
+                gameday_id: None,
+            })
+            .collect();
+
+        let template = GamedayTemplate {
+            _id: ObjectId::new(),
+            name,
+            initial_player_credits: gameday.initial_player_credits,
+            transfer_cap: gameday.transfer_cap,
+            games,
+            created_at: DateTime::now(),
+        };
+
+        let client = data_source.get_new_db_client().await?;
+        let db = client.database(data_source.database_identifier);
+        let collection: Collection<GamedayTemplate> =
+            db.collection(data_source.collection_identifier);
+
+        collection.insert_one(&template).await?;
+
+        Ok(template)
+    }
+
+    /// Creates a new draft gameday with copies of `games`, which are the games of the template
+    /// with any overrides applied. If the games can not be created the gameday is removed again,
+    /// so no half copied gameday is left behind.
+    pub async fn create_gameday(
+        &self,
+        name: String,
+        initial_player_credits: u64,
+        games: Vec<data_source::Game>,
+        gameday_data_source: DataSource,
+        game_data_source: DataSource,
+    ) -> Result<ObjectId, Error> {
+        let client = gameday_data_source.get_new_db_client().await?;
+        let collection: Collection<Gameday> = client
+            .database(gameday_data_source.database_identifier)
+            .collection(gameday_data_source.collection_identifier);
+
+        let gameday_id =
+            Gameday::new(initial_player_credits, name, self.transfer_cap, &collection).await?;
+
+        if let Err(e) = Game::new_many(games, Some(gameday_id), game_data_source).await {
+            if let Err(cleanup_error) = collection.delete_one(doc! { "_id": gameday_id }).await {
+                error!(
+                    "Could not remove gameday {} after its games failed: {}",
+                    gameday_id, cleanup_error
+                );
+            }
+            return Err(e);
+        }
+
+        Ok(gameday_id)
+    }
+
+    pub async fn get(_id: &ObjectId, data_source: DataSource) -> Result<Option<Self>, Error> {
+        let client = data_source.get_new_db_client().await?;
+        let db = client.database(data_source.database_identifier);
+        let collection: Collection<GamedayTemplate> =
+            db.collection(data_source.collection_identifier);
+
+        collection.find_one(doc! { "_id": _id }).await
+    }
+
+    pub async fn get_all(data_source: DataSource) -> Result<Vec<Self>, Error> {
+        let client = data_source.get_new_db_client().await?;
+        let db = client.database(data_source.database_identifier);
+        let collection: Collection<GamedayTemplate> =
+            db.collection(data_source.collection_identifier);
+
+        collection
+            .find(doc! {})
+            .sort(doc! { "created_at": -1 })
+            .await?
+            .try_collect()
+            .await
+    }
+
+    pub fn get_json_value(&self) -> serde_json::Value {
+        serde_json::json!({
+            "_id": self._id.to_string(),
+            "name": self.name,
+            "initial_player_credits": self.initial_player_credits,
+            "transfer_cap": self.transfer_cap,
+            "games": self.games.iter().map(|g| serde_json::json!({
+                "name": g.name,
+                "icon_id": g.icon_id,
+                "join_fee": g.join_fee,
+                "description": g.description,
+                "outcomes": g.payouts.outcomes,
+                "min_stake": g.payouts.min_stake,
+                "max_stake": g.payouts.max_stake,
+                "house_edge": g.payouts.house_edge,
+            })).collect::<Vec<serde_json::Value>>(),
+            "created_at": self.created_at.try_to_rfc3339_string().unwrap_or_default(),
+        })
+    }
+}
@@ -0,0 +1,113 @@
+use crate::data_source::user::is_duplicate_key;
+use crate::data_source::DataSource;
+use mongodb::bson::{doc, DateTime};
+use mongodb::error::Error;
+use mongodb::Collection;
+use serde::{Deserialize, Serialize};
+use std::env;
+
+const DEFAULT_IDEMPOTENCY_KEY_TTL_HOURS: i64 = 24;
+/// How long a key stays claimed by a request that has not finished. If the server dies
+/// mid-request the key becomes usable again after this.
+const RESERVATION_LEASE_SECS: i64 = 60;
+
+/// Response stored for an Idempotency-Key. `status` is unset while the first request is still running,
+/// until then `expires_at` is the end of its lease.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct IdempotencyRecord {
+    pub(crate) _id: String,
+    pub(crate) status: Option<u16>,
+    pub(crate) content_type: Option<String>,
+    pub(crate) body: Option<String>,
+    pub(crate) expires_at: DateTime,
+}
+
+pub fn idempotency_key_ttl_hours() -> i64 {
+    env::var("IDEMPOTENCY_KEY_TTL_HOURS")
+        .ok()
+        .and_then(|h| h.parse::<i64>().ok())
+        .filter(|h| *h > 0)
+        .unwrap_or(DEFAULT_IDEMPOTENCY_KEY_TTL_HOURS)
+}
+
+impl IdempotencyRecord {
+    /// Claims the key for a new request. Returns `None` if the key was unused or its lease ran
+    /// out, otherwise the record of the request that claimed it first.
+    pub async fn reserve(id: &str, data_source: DataSource) -> Result<Option<Self>, Error> {
+        let client = data_source.get_new_db_client().await?;
+        let db = client.database(data_source.database_identifier);
+        let collection: Collection<IdempotencyRecord> =
+            db.collection(data_source.collection_identifier);
+
+        let now = DateTime::now();
+        let lease_end =
+            DateTime::from_millis(now.timestamp_millis() + RESERVATION_LEASE_SECS * 1000);
+
+        let insert_doc = IdempotencyRecord {
+            _id: id.to_string(),
+            status: None,
+            content_type: None,
+            body: None,
+            expires_at: lease_end,
+        };
+
+        match collection.insert_one(&insert_doc).await {
+            Ok(_) => Ok(None),
+            Err(e) if is_duplicate_key(&e) => {
+                // The TTL monitor only runs periodically, an abandoned claim is taken over here
+                let abandoned = doc! { "_id": id, "status": null, "expires_at": { "$lte": now } };
+                let res = collection
+                    .update_one(abandoned, doc! { "$set": { "expires_at": lease_end } })
+                    .await?;
+
+                match res.modified_count {
+                    0 => collection.find_one(doc! { "_id": id }).await,
+                    _ => Ok(None),
+                }
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Stores the response of the request that claimed the key, which is kept for
+    /// IDEMPOTENCY_KEY_TTL_HOURS.
+    pub async fn complete(
+        id: &str,
+        status: u16,
+        content_type: Option<String>,
+        body: String,
+        data_source: DataSource,
+    ) -> Result<(), Error> {
+        let client = data_source.get_new_db_client().await?;
+        let db = client.database(data_source.database_identifier);
+        let collection: Collection<IdempotencyRecord> =
+            db.collection(data_source.collection_identifier);
+
+        let lifetime_ms = idempotency_key_ttl_hours() * 60 * 60 * 1000;
+        let expires_at = DateTime::from_millis(DateTime::now().timestamp_millis() + lifetime_ms);
+
+        let update = doc! {
+            "$set": {
+                "status": status as i32,
+                "content_type": content_type,
+                "body": body,
+                "expires_at": expires_at,
+            },
+        };
+        collection.update_one(doc! { "_id": id }, update).await?;
+
+        Ok(())
+    }
+
+    /// Frees the key again, so a failed request can be retried with it.
+    pub async fn release(id: &str, data_source: DataSource) -> Result<(), Error> {
+        let client = data_source.get_new_db_client().await?;
+        let db = client.database(data_source.database_identifier);
+        let collection: Collection<IdempotencyRecord> =
+            db.collection(data_source.collection_identifier);
+
+        collection.delete_one(doc! { "_id": id }).await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,166 @@
+use crate::data_source::DataSource;
+use futures::TryStreamExt;
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{doc, DateTime};
+use mongodb::error::Error;
+use mongodb::options::ReturnDocument;
+use mongodb::Collection;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+
+/// Failed attempts that are allowed before the first lockout kicks in.
+const FREE_ATTEMPTS: u32 = 5;
+const BASE_LOCKOUT_SECS: i64 = 30;
+const MAX_LOCKOUT_SECS: i64 = 60 * 60;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LoginAttempt {
+    pub(crate) _id: String,
+    pub(crate) failures: u32,
+    pub(crate) locked_until: Option<DateTime>,
+    pub(crate) last_failure: DateTime,
+}
+
+/// What a counter of failed attempts is kept for.
+pub enum AttemptKey {
+    Player(ObjectId),
+    Pin(String),
+    Ip(String),
+    Dealer(String),
+}
+
+impl Display for AttemptKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AttemptKey::Player(id) => write!(f, "player:{}", id),
+            AttemptKey::Pin(pin) => write!(f, "pin:{}", pin),
+            AttemptKey::Ip(ip) => write!(f, "ip:{}", ip),
+            AttemptKey::Dealer(name) => write!(f, "dealer:{}", name),
+        }
+    }
+}
+
+/// Length of the lockout after the given number of failures, `None` while attempts are free.
+fn lockout_secs(failures: u32) -> Option<i64> {
+    if failures < FREE_ATTEMPTS {
+        return None;
+    }
+
+    let exponent = (failures - FREE_ATTEMPTS).min(16);
+    Some((BASE_LOCKOUT_SECS << exponent).min(MAX_LOCKOUT_SECS))
+}
+
+impl LoginAttempt {
+    /// Returns the latest point in time until which any of the keys is locked.
+    pub async fn locked_until(
+        keys: &[&AttemptKey],
+        data_source: DataSource,
+    ) -> Result<Option<DateTime>, Error> {
+        let client = data_source.get_new_db_client().await?;
+        let db = client.database(data_source.database_identifier);
+        let collection: Collection<LoginAttempt> = db.collection(data_source.collection_identifier);
+
+        let ids: Vec<String> = keys.iter().map(|k| k.to_string()).collect();
+        let filter = doc! {
+            "_id": { "$in": ids },
+            "locked_until": { "$gt": DateTime::now() },
+        };
+
+        let res = collection
+            .find_one(filter)
+            .sort(doc! { "locked_until": -1 })
+            .await?;
+
+        Ok(res.and_then(|a| a.locked_until))
+    }
+
+    pub async fn get_locked(data_source: DataSource) -> Result<Vec<Self>, Error> {
+        let client = data_source.get_new_db_client().await?;
+        let db = client.database(data_source.database_identifier);
+        let collection: Collection<LoginAttempt> = db.collection(data_source.collection_identifier);
+
+        let filter = doc! { "locked_until": { "$gt": DateTime::now() } };
+        let res = collection.find(filter).await?.try_collect().await?;
+
+        Ok(res)
+    }
+
+    /// Counts a failed attempt for the key. Once the free attempts are used up every
+    /// further failure doubles the lockout. Returns the new lockout end if the key is now locked.
+    pub async fn record_failure(
+        key: &AttemptKey,
+        data_source: DataSource,
+    ) -> Result<Option<DateTime>, Error> {
+        let client = data_source.get_new_db_client().await?;
+        let db = client.database(data_source.database_identifier);
+        let collection: Collection<LoginAttempt> = db.collection(data_source.collection_identifier);
+
+        let now = DateTime::now();
+        let filter = doc! { "_id": key.to_string() };
+        let update = doc! {
+            "$inc": { "failures": 1 },
+            "$set": { "last_failure": now },
+        };
+
+        let attempt = collection
+            .find_one_and_update(filter.clone(), update)
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .await?;
+
+        let failures = match attempt {
+            Some(a) => a.failures,
+            None => return Ok(None),
+        };
+
+        let lockout_secs = match lockout_secs(failures) {
+            Some(secs) => secs,
+            None => return Ok(None),
+        };
+        let locked_until = DateTime::from_millis(now.timestamp_millis() + lockout_secs * 1000);
+
+        collection
+            .update_one(filter, doc! { "$set": { "locked_until": locked_until } })
+            .await?;
+
+        Ok(Some(locked_until))
+    }
+
+    /// Forgets all failed attempts of the key. Returns whether there was anything to forget.
+    pub async fn clear(key: &AttemptKey, data_source: DataSource) -> Result<bool, Error> {
+        let client = data_source.get_new_db_client().await?;
+        let db = client.database(data_source.database_identifier);
+        let collection: Collection<LoginAttempt> = db.collection(data_source.collection_identifier);
+
+        let res = collection
+            .delete_one(doc! { "_id": key.to_string() })
+            .await?;
+
+        Ok(res.deleted_count == 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn free_attempts_do_not_lock() {
+        for failures in 0..FREE_ATTEMPTS {
+            assert_eq!(lockout_secs(failures), None);
+        }
+    }
+
+    #[test]
+    fn lockout_doubles_per_failure() {
+        assert_eq!(lockout_secs(FREE_ATTEMPTS), Some(BASE_LOCKOUT_SECS));
+        assert_eq!(lockout_secs(FREE_ATTEMPTS + 1), Some(BASE_LOCKOUT_SECS * 2));
+        assert_eq!(lockout_secs(FREE_ATTEMPTS + 2), Some(BASE_LOCKOUT_SECS * 4));
+    }
+
+    #[test]
+    fn lockout_is_capped() {
+        assert_eq!(lockout_secs(FREE_ATTEMPTS + 10), Some(MAX_LOCKOUT_SECS));
+        assert_eq!(lockout_secs(u32::MAX), Some(MAX_LOCKOUT_SECS));
+    }
+}
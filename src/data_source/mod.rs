@@ -1,13 +1,46 @@
 use crate::DATABASE_IDENT;
+use log::info;
+use mongodb::bson::doc;
 use mongodb::Client;
 use std::env;
 use std::fmt::{Display, Formatter};
 use std::io::{Error, ErrorKind};
+use std::sync::OnceLock;
 
+pub mod audit_log;
+pub mod bet;
 pub(crate) mod game;
 pub mod gameday;
+pub mod gameday_template;
+pub mod idempotency;
+pub mod login_attempt;
+pub mod session;
+pub mod standings;
+pub mod transaction;
 pub mod user;
 
+static TRANSACTIONS_SUPPORTED: OnceLock<bool> = OnceLock::new();
+
+/// Checks once at startup whether the deployment can run multi-document transactions,
+/// which needs a replica set or a sharded cluster.
+pub async fn detect_transaction_support(data_source: DataSource) -> Result<bool, Error> {
+    let client = data_source.get_new_db_client().await?;
+    let hello = client
+        .database(data_source.database_identifier)
+        .run_command(doc! { "hello": 1 })
+        .await
+        .map_err(|e| Error::new(ErrorKind::ConnectionRefused, format!("Database: {}", e)))?;
+
+    let supported = hello.contains_key("setName") || hello.get_str("msg") == Ok("isdbgrid");
+    info!("Transactions supported: {}", supported);
+
+    Ok(*TRANSACTIONS_SUPPORTED.get_or_init(|| supported))
+}
+
+pub fn transactions_supported() -> bool {
+    TRANSACTIONS_SUPPORTED.get().copied().unwrap_or(false)
+}
+
 pub struct DataSource {
     pub database_identifier: &'static str,
     pub collection_identifier: &'static str,
@@ -33,6 +66,46 @@ pub const GAMES: DataSource = DataSource {
     collection_identifier: "games",
 };
 
+pub const LOGIN_ATTEMPTS: DataSource = DataSource {
+    database_identifier: DATABASE_IDENT,
+    collection_identifier: "login_attempts",
+};
+
+pub const AUDIT_LOG: DataSource = DataSource {
+    database_identifier: DATABASE_IDENT,
+    collection_identifier: "audit_log",
+};
+
+pub const TRANSACTIONS: DataSource = DataSource {
+    database_identifier: DATABASE_IDENT,
+    collection_identifier: "transactions",
+};
+
+pub const IDEMPOTENCY_KEYS: DataSource = DataSource {
+    database_identifier: DATABASE_IDENT,
+    collection_identifier: "idempotency_keys",
+};
+
+pub const BETS: DataSource = DataSource {
+    database_identifier: DATABASE_IDENT,
+    collection_identifier: "bets",
+};
+
+pub const STANDINGS: DataSource = DataSource {
+    database_identifier: DATABASE_IDENT,
+    collection_identifier: "standings",
+};
+
+pub const GAMEDAY_TEMPLATES: DataSource = DataSource {
+    database_identifier: DATABASE_IDENT,
+    collection_identifier: "gameday_templates",
+};
+
+pub const SESSIONS: DataSource = DataSource {
+    database_identifier: DATABASE_IDENT,
+    collection_identifier: "sessions",
+};
+
 impl DataSource {
     pub async fn get_new_db_client(&self) -> Result<mongodb::Client, Error> {
         let mongo_uri = env::var("CUSTOMCONNSTR_MONGO_URI");
@@ -62,25 +135,29 @@ impl DataSource {
 
 use crate::data_source::user::{Dealer, Player};
 use mongodb::bson::oid::ObjectId;
-use serde::{Deserialize, Serialize};
+use mongodb::bson::DateTime;
+use serde::{Deserialize, Deserializer, Serialize};
 
 #[derive(Deserialize)]
 pub(crate) struct Gameday {
     pub(crate) name: String,
     pub(crate) initial_player_credits: u64,
+    pub(crate) transfer_cap: Option<u64>,
 }
 
 #[derive(Deserialize)]
 pub struct RegisterUser {
     pub(crate) nickname: String,
     pub(crate) name: String,
-    pub(crate) pin: u32,
+    #[serde(deserialize_with = "deserialize_pin")]
+    pub(crate) pin: String,
 }
 
 #[derive(Deserialize)]
 pub struct RegisterDealer {
     pub(crate) name: String,
     pub password: String,
+    pub(crate) role: Option<Roles>,
 }
 
 #[derive(Deserialize)]
@@ -89,21 +166,117 @@ pub struct LoginDealer {
     pub password: String,
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize)]
+pub struct ChangePassword {
+    pub old_password: String,
+    pub new_password: String,
+}
+
+#[derive(Deserialize)]
+pub struct ResetPassword {
+    pub new_password: String,
+}
+
+#[derive(Deserialize)]
+pub struct DealerStatus {
+    pub(crate) disabled: bool,
+}
+
+/// Pins used to be stored as plain numbers, newer ones are strings. Stored numbers are converted
+/// at startup, both are still accepted from requests.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PinRepr {
+    Text(String),
+    Number(i64),
+}
+
+impl From<PinRepr> for String {
+    fn from(value: PinRepr) -> Self {
+        match value {
+            PinRepr::Text(t) => t.trim().to_uppercase(),
+            PinRepr::Number(n) => n.to_string(),
+        }
+    }
+}
+
+fn deserialize_pin<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    Ok(PinRepr::deserialize(deserializer)?.into())
+}
+
+fn deserialize_optional_pin<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<String>, D::Error> {
+    let pin = Option::<PinRepr>::deserialize(deserializer)?;
+
+    Ok(match pin {
+        // dealers used to be stored with a pin of 0
+        Some(PinRepr::Number(0)) | None => None,
+        Some(pin) => Some(pin.into()),
+    })
+}
+
+#[derive(Deserialize)]
+pub struct RecoverPin {
+    /// Gameday the player is looked up in.
+    pub(crate) gameday_id: String,
+    pub(crate) user_id: Option<String>,
+    pub(crate) name: Option<String>,
+    pub(crate) nickname: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct TransferCredits {
+    /// `_id` or nickname of the receiving player.
+    pub(crate) recipient: String,
+    pub(crate) amount: u64,
+    /// Authorizes the transfer together with `sender_id` if the sender has no session.
+    #[serde(default, deserialize_with = "deserialize_optional_pin")]
+    pub(crate) pin: Option<String>,
+    pub(crate) sender_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ClearLockout {
+    pub(crate) player_id: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_optional_pin")]
+    pub(crate) pin: Option<String>,
+    pub(crate) ip: Option<String>,
+    pub(crate) dealer_name: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
 pub struct DBUser {
     pub(crate) _id: ObjectId,
     pub(crate) nickname: Option<String>,
     pub(crate) name: Option<String>,
-    pub(crate) pin: u32,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_optional_pin",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) pin: Option<String>,
     pub(crate) credits: Option<u64>,
     pub(crate) password: Option<String>,
     pub(crate) role: Roles,
     pub(crate) active_game: Option<ObjectId>,
+    #[serde(default)]
+    pub(crate) disabled: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) gameday_id: Option<ObjectId>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) created_at: Option<DateTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) expires_at: Option<DateTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) joined_at: Option<DateTime>,
 }
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
 pub enum Roles {
     Player,
     Dealer,
+    Cashier,
+    Admin,
 }
 
 impl Display for Roles {
@@ -112,6 +285,60 @@ impl Display for Roles {
     }
 }
 
+/// Actions a staff member can be allowed to perform.
+/// Each permission needs a minimum role, higher roles inherit the permissions of lower ones.
+#[derive(Debug, Clone, Copy)]
+pub enum Permission {
+    CreatePins,
+    ManageGames,
+    RevokePlayerSessions,
+    AdjustCredits,
+    ChangeCredits,
+    ManageGamedays,
+    ManageDealers,
+    ClearLockouts,
+    RecoverPins,
+    ViewAuditLog,
+    PauseGamedays,
+}
+
+impl Permission {
+    fn required_role(&self) -> Roles {
+        match self {
+            Permission::CreatePins => Roles::Dealer,
+            Permission::ManageGames => Roles::Dealer,
+            Permission::RevokePlayerSessions => Roles::Dealer,
+            Permission::AdjustCredits => Roles::Cashier,
+            Permission::ChangeCredits => Roles::Admin,
+            Permission::ManageGamedays => Roles::Admin,
+            Permission::ManageDealers => Roles::Admin,
+            Permission::ClearLockouts => Roles::Admin,
+            Permission::RecoverPins => Roles::Dealer,
+            Permission::ViewAuditLog => Roles::Dealer,
+            Permission::PauseGamedays => Roles::Dealer,
+        }
+    }
+}
+
+impl Roles {
+    fn rank(&self) -> u8 {
+        match self {
+            Roles::Player => 0,
+            Roles::Dealer => 1,
+            Roles::Cashier => 2,
+            Roles::Admin => 3,
+        }
+    }
+
+    pub fn is_staff(&self) -> bool {
+        self.rank() >= Roles::Dealer.rank()
+    }
+
+    pub fn has_permission(&self, permission: Permission) -> bool {
+        self.rank() >= permission.required_role().rank()
+    }
+}
+
 impl From<user::User> for DBUser {
     fn from(value: user::User) -> Self {
         match value {
@@ -119,56 +346,152 @@ impl From<user::User> for DBUser {
                 _id: player._id,
                 nickname: player.nickname,
                 name: player.name,
-                pin: player.pin,
+                pin: Some(player.pin),
                 credits: Some(player.credits),
                 password: None,
                 role: Roles::Player,
                 active_game: player.active_game,
+                disabled: false,
+                gameday_id: player.gameday_id,
+                created_at: player.created_at,
+                expires_at: player.expires_at,
+                joined_at: player.joined_at,
             },
             user::User::Dealer(dealer) => DBUser {
                 _id: dealer._id,
                 nickname: None,
                 name: Some(dealer.name),
-                pin: 0,
+                pin: None,
                 credits: None,
                 password: Some(dealer.password),
-                role: Roles::Dealer,
+                role: dealer.role,
                 active_game: None,
+                disabled: dealer.disabled,
+                gameday_id: None,
+                created_at: None,
+                expires_at: None,
+                joined_at: None,
             },
         }
     }
 }
 
-impl Into<user::User> for DBUser {
-    fn into(self) -> user::User {
-        match self.role {
-            Roles::Player => user::User::Player(self.into()),
-            Roles::Dealer => user::User::Dealer(Dealer {
-                name: self.name.expect("Dealer has no name"),
-                _id: self._id,
-                password: self.password.expect("Dealer has no password"),
+impl From<DBUser> for user::User {
+    fn from(value: DBUser) -> Self {
+        match value.role {
+            Roles::Player => user::User::Player(value.into()),
+            Roles::Dealer | Roles::Cashier | Roles::Admin => user::User::Dealer(Dealer {
+                name: value.name.expect("Dealer has no name"),
+                _id: value._id,
+                password: value.password.expect("Dealer has no password"),
+                role: value.role,
+                disabled: value.disabled,
             }),
         }
     }
 }
 
-impl Into<Player> for DBUser {
-    fn into(self) -> Player {
+impl From<DBUser> for Player {
+    fn from(value: DBUser) -> Self {
         Player {
-            name: self.name,
-            nickname: self.nickname,
-            _id: self._id,
-            credits: self.credits.unwrap_or(0),
-            pin: self.pin,
-            active_game: self.active_game,
+            name: value.name,
+            nickname: value.nickname,
+            _id: value._id,
+            credits: value.credits.unwrap_or(0),
+            pin: value.pin.unwrap_or_default(),
+            active_game: value.active_game,
+            gameday_id: value.gameday_id,
+            created_at: value.created_at,
+            expires_at: value.expires_at,
+            joined_at: value.joined_at,
         }
     }
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, Debug)]
 pub struct Game {
     pub(crate) name: String,
     pub(crate) icon_id: String,
     pub(crate) join_fee: u64,
     pub description: String,
+    #[serde(flatten)]
+    pub(crate) payouts: game::PayoutSchema,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) gameday_id: Option<ObjectId>,
+}
+
+impl Game {
+    pub fn validate(&self) -> Result<(), String> {
+        if u32::try_from(self.join_fee).is_err() {
+            return Err("Join fee is too large".to_string());
+        }
+
+        self.payouts.validate()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pin_from_json(json: &str) -> String {
+        let mut deserializer = serde_json::Deserializer::from_str(json);
+        deserialize_pin(&mut deserializer).unwrap()
+    }
+
+    #[test]
+    fn pins_sent_as_numbers_match_generated_pins() {
+        let format = user::PinFormat::from_env();
+        for _ in 0..1000 {
+            let pin = format.generate();
+            assert!(!pin.starts_with('0'), "{}", pin);
+            if pin.chars().all(|c| c.is_ascii_digit()) {
+                assert_eq!(pin_from_json(&pin), pin);
+            }
+        }
+    }
+
+    #[test]
+    fn leading_zero_is_lost_when_sent_as_number() {
+        // "012345" can only be sent as the number 12345, which is why pins never start with '0'
+        assert_eq!(pin_from_json("12345"), "12345");
+        assert_eq!(pin_from_json("\"012345\""), "012345");
+    }
+
+    #[test]
+    fn pins_are_normalized() {
+        assert_eq!(pin_from_json("\" ab3k \""), "AB3K");
+    }
+
+    #[test]
+    fn higher_roles_inherit_permissions() {
+        assert!(Roles::Dealer.has_permission(Permission::CreatePins));
+        assert!(Roles::Cashier.has_permission(Permission::CreatePins));
+        assert!(Roles::Admin.has_permission(Permission::CreatePins));
+    }
+
+    #[test]
+    fn lower_roles_lack_permissions() {
+        assert!(!Roles::Player.has_permission(Permission::CreatePins));
+        assert!(!Roles::Dealer.has_permission(Permission::ManageDealers));
+        assert!(!Roles::Cashier.has_permission(Permission::ManageDealers));
+        assert!(Roles::Admin.has_permission(Permission::ManageDealers));
+    }
+
+    #[test]
+    fn only_cashiers_and_admins_change_credits() {
+        for permission in [Permission::AdjustCredits, Permission::ChangeCredits] {
+            assert!(!Roles::Dealer.has_permission(permission));
+            assert!(Roles::Admin.has_permission(permission));
+        }
+        assert!(Roles::Cashier.has_permission(Permission::AdjustCredits));
+    }
+
+    #[test]
+    fn only_staff_is_staff() {
+        assert!(!Roles::Player.is_staff());
+        assert!(Roles::Dealer.is_staff());
+        assert!(Roles::Cashier.is_staff());
+        assert!(Roles::Admin.is_staff());
+    }
 }
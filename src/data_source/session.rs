@@ -0,0 +1,178 @@
+use crate::data_source::DataSource;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use hmac::{Hmac, Mac};
+use log::warn;
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{doc, DateTime};
+use mongodb::error::Error;
+use mongodb::Collection;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::env;
+use std::sync::OnceLock;
+
+const DEFAULT_DEALER_SESSION_HOURS: i64 = 12;
+const DEFAULT_PLAYER_SESSION_HOURS: i64 = 24;
+
+static SESSION_SECRET: OnceLock<Vec<u8>> = OnceLock::new();
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Session {
+    pub(crate) _id: ObjectId,
+    pub(crate) token_hash: String,
+    pub(crate) user_id: ObjectId,
+    pub(crate) created_at: DateTime,
+    pub(crate) expires_at: DateTime,
+}
+
+impl Session {
+    /// Creates a new session for the given user and returns it together with the signed token.
+    /// Only the SHA-256 hash of the token is stored, the plain token is handed out once.
+    pub async fn new(
+        user_id: ObjectId,
+        lifetime_hours: i64,
+        data_source: DataSource,
+    ) -> Result<(Self, String), Error> {
+        let client = data_source.get_new_db_client().await?;
+        let db = client.database(data_source.database_identifier);
+        let collection: Collection<Session> = db.collection(data_source.collection_identifier);
+
+        let mut bytes = [0u8; 32];
+        OsRng.fill_bytes(&mut bytes);
+        let payload = hex::encode(bytes);
+        let token = format!("{}.{}", payload, hex::encode(sign(payload.as_bytes())));
+
+        let now = DateTime::now();
+        let lifetime_ms = lifetime_hours * 60 * 60 * 1000;
+
+        let insert_doc = Session {
+            _id: ObjectId::new(),
+            token_hash: hash_token(&token),
+            user_id,
+            created_at: now,
+            expires_at: DateTime::from_millis(now.timestamp_millis() + lifetime_ms),
+        };
+
+        collection.insert_one(&insert_doc).await?;
+
+        Ok((insert_doc, token))
+    }
+
+    /// Returns the session belonging to the token if it exists and has not expired yet.
+    /// Tokens with an invalid signature are rejected without touching the database.
+    pub async fn get_valid(token: &str, data_source: DataSource) -> Result<Option<Self>, Error> {
+        if !is_signature_valid(token) {
+            return Ok(None);
+        }
+
+        let client = data_source.get_new_db_client().await?;
+        let db = client.database(data_source.database_identifier);
+        let collection: Collection<Session> = db.collection(data_source.collection_identifier);
+
+        let filter = doc! {
+            "token_hash": hash_token(token),
+            "expires_at": { "$gt": DateTime::now() },
+        };
+
+        collection.find_one(filter).await
+    }
+
+    pub async fn revoke(token: &str, data_source: DataSource) -> Result<bool, Error> {
+        let client = data_source.get_new_db_client().await?;
+        let db = client.database(data_source.database_identifier);
+        let collection: Collection<Session> = db.collection(data_source.collection_identifier);
+
+        let res = collection
+            .delete_one(doc! { "token_hash": hash_token(token) })
+            .await?;
+
+        Ok(res.deleted_count == 1)
+    }
+
+    /// Revokes every session of the user except the one of `keep_token`.
+    pub async fn revoke_others(
+        user_id: ObjectId,
+        keep_token: &str,
+        data_source: DataSource,
+    ) -> Result<u64, Error> {
+        let client = data_source.get_new_db_client().await?;
+        let db = client.database(data_source.database_identifier);
+        let collection: Collection<Session> = db.collection(data_source.collection_identifier);
+
+        let filter = doc! {
+            "user_id": user_id,
+            "token_hash": { "$ne": hash_token(keep_token) },
+        };
+        let res = collection.delete_many(filter).await?;
+
+        Ok(res.deleted_count)
+    }
+
+    pub async fn revoke_all(user_id: ObjectId, data_source: DataSource) -> Result<u64, Error> {
+        let client = data_source.get_new_db_client().await?;
+        let db = client.database(data_source.database_identifier);
+        let collection: Collection<Session> = db.collection(data_source.collection_identifier);
+
+        let res = collection.delete_many(doc! { "user_id": user_id }).await?;
+
+        Ok(res.deleted_count)
+    }
+}
+
+pub fn dealer_session_hours() -> i64 {
+    hours_from_env("DEALER_SESSION_HOURS", DEFAULT_DEALER_SESSION_HOURS)
+}
+
+pub fn player_session_hours() -> i64 {
+    hours_from_env("PLAYER_SESSION_HOURS", DEFAULT_PLAYER_SESSION_HOURS)
+}
+
+fn hours_from_env(var: &str, default: i64) -> i64 {
+    env::var(var)
+        .ok()
+        .and_then(|h| h.parse::<i64>().ok())
+        .filter(|h| *h > 0)
+        .unwrap_or(default)
+}
+
+/// The signing secret is read from SESSION_SECRET. Without it a random secret is used,
+/// which invalidates all issued tokens on restart.
+fn session_secret() -> &'static [u8] {
+    SESSION_SECRET.get_or_init(|| match env::var("SESSION_SECRET") {
+        Ok(secret) if !secret.is_empty() => secret.into_bytes(),
+        _ => {
+            warn!("SESSION_SECRET not set - using a random secret, sessions will not survive a restart");
+            let mut bytes = vec![0u8; 32];
+            OsRng.fill_bytes(&mut bytes);
+            bytes
+        }
+    })
+}
+
+fn sign(payload: &[u8]) -> Vec<u8> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(session_secret()).expect("HMAC can take a key of any size");
+    mac.update(payload);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn is_signature_valid(token: &str) -> bool {
+    let (payload, signature) = match token.split_once('.') {
+        Some(parts) => parts,
+        None => return false,
+    };
+
+    let signature = match hex::decode(signature) {
+        Ok(s) => s,
+        Err(_) => return false,
+    };
+
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(session_secret()).expect("HMAC can take a key of any size");
+    mac.update(payload.as_bytes());
+    mac.verify_slice(&signature).is_ok()
+}
+
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
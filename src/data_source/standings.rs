@@ -0,0 +1,160 @@
+use crate::data_source::gameday::{Gameday, GamedayStatus};
+use crate::data_source::user::{is_duplicate_key, User};
+use crate::data_source::{DataSource, Roles};
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{doc, DateTime};
+use mongodb::error::Error;
+use mongodb::Collection;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StandingsEntry {
+    pub(crate) rank: u32,
+    pub(crate) user_id: ObjectId,
+    pub(crate) nickname: Option<String>,
+    pub(crate) credits: u64,
+}
+
+/// Balances of all players of a gameday at the moment it was closed. Stored under the id of the
+/// gameday and never changed afterwards, so it outlives the player data.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Standings {
+    pub(crate) _id: ObjectId,
+    pub(crate) gameday_name: String,
+    pub(crate) entries: Vec<StandingsEntry>,
+    pub(crate) created_at: DateTime,
+}
+
+/// Orders the players by their credits, highest first. Players with the same balance share a
+/// rank, the next rank is skipped accordingly.
+fn rank(mut players: Vec<(ObjectId, Option<String>, u64)>) -> Vec<StandingsEntry> {
+    players.sort_by_key(|p| std::cmp::Reverse(p.2));
+
+    let mut entries: Vec<StandingsEntry> = vec![];
+    for (i, (user_id, nickname, credits)) in players.into_iter().enumerate() {
+        let rank = match entries.last() {
+            Some(prev) if prev.credits == credits => prev.rank,
+            _ => i as u32 + 1,
+        };
+        entries.push(StandingsEntry {
+            rank,
+            user_id,
+            nickname,
+            credits,
+        });
+    }
+
+    entries
+}
+
+impl Standings {
+    /// Freezes the current balances of the players of the gameday. If the standings were frozen
+    /// before, the existing ones are returned unchanged. An archived gameday without players is
+    /// refused, its players may have been removed already and empty standings would stick.
+    pub async fn freeze(
+        gameday: &Gameday,
+        player_data_source: DataSource,
+        data_source: DataSource,
+    ) -> Result<Self, Error> {
+        let gameday_id = gameday._id;
+        let players: Vec<(ObjectId, Option<String>, u64)> =
+            User::get_by_role(Roles::Player, Some(gameday_id), player_data_source)
+                .await?
+                .into_iter()
+                .map(|u| (u._id, u.nickname, u.credits.unwrap_or(0)))
+                .collect();
+
+        if players.is_empty() && gameday.status == GamedayStatus::Archived {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("Archived gameday {} has no players to rank", gameday_id),
+            )
+            .into());
+        }
+
+        let standings = Standings {
+            _id: gameday_id,
+            gameday_name: gameday.name.clone(),
+            entries: rank(players),
+            created_at: DateTime::now(),
+        };
+
+        let client = data_source.get_new_db_client().await?;
+        let db = client.database(data_source.database_identifier);
+        let collection: Collection<Standings> = db.collection(data_source.collection_identifier);
+
+        match collection.insert_one(&standings).await {
+            Ok(_) => Ok(standings),
+            Err(e) if is_duplicate_key(&e) => {
+                let existing = collection.find_one(doc! { "_id": gameday_id }).await?;
+                Ok(existing.unwrap_or(standings))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    pub async fn get(
+        gameday_id: &ObjectId,
+        data_source: DataSource,
+    ) -> Result<Option<Self>, Error> {
+        let client = data_source.get_new_db_client().await?;
+        let db = client.database(data_source.database_identifier);
+        let collection: Collection<Standings> = db.collection(data_source.collection_identifier);
+
+        collection.find_one(doc! { "_id": gameday_id }).await
+    }
+
+    pub fn get_json_value(&self) -> serde_json::Value {
+        serde_json::json!({
+            "gameday_id": self._id.to_string(),
+            "gameday_name": self.gameday_name,
+            "created_at": self.created_at.try_to_rfc3339_string().unwrap_or_default(),
+            "standings": self.entries.iter().map(|e| serde_json::json!({
+                "rank": e.rank,
+                "user_id": e.user_id.to_string(),
+                "nickname": e.nickname,
+                "credits": e.credits,
+            })).collect::<Vec<serde_json::Value>>(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranks(credits: &[u64]) -> Vec<(u32, u64)> {
+        let players = credits
+            .iter()
+            .map(|c| (ObjectId::new(), None, *c))
+            .collect();
+
+        rank(players)
+            .into_iter()
+            .map(|e| (e.rank, e.credits))
+            .collect()
+    }
+
+    #[test]
+    fn highest_balance_ranks_first() {
+        assert_eq!(ranks(&[10, 30, 20]), vec![(1, 30), (2, 20), (3, 10)]);
+    }
+
+    #[test]
+    fn tied_players_share_a_rank() {
+        assert_eq!(
+            ranks(&[50, 20, 50, 20, 10]),
+            vec![(1, 50), (1, 50), (3, 20), (3, 20), (5, 10)]
+        );
+    }
+
+    #[test]
+    fn all_tied() {
+        assert_eq!(ranks(&[0, 0, 0]), vec![(1, 0), (1, 0), (1, 0)]);
+    }
+
+    #[test]
+    fn no_players() {
+        assert!(ranks(&[]).is_empty());
+    }
+}
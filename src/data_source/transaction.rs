@@ -0,0 +1,318 @@
+use crate::data_source;
+use crate::data_source::{DBUser, DataSource};
+use futures::TryStreamExt;
+use log::warn;
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{doc, DateTime, Document};
+use mongodb::error::Error;
+use mongodb::{ClientSession, Collection};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum TransactionKind {
+    BuyIn,
+    JoinFee,
+    FeeRefund,
+    DealerPayout,
+    Charge,
+    TransferOut,
+    TransferIn,
+    BetStake,
+    BetPayout,
+    BetRefund,
+    ManualCorrection,
+    /// Credits a player had before the ledger was introduced.
+    OpeningBalance,
+}
+
+impl Display for TransactionKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// One change of a players credits. `amount` is signed, `balance` is the balance after the change.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Transaction {
+    pub(crate) _id: ObjectId,
+    pub(crate) user_id: ObjectId,
+    pub(crate) kind: TransactionKind,
+    pub(crate) amount: i64,
+    pub(crate) balance: i64,
+    pub(crate) game_id: Option<ObjectId>,
+    pub(crate) actor_id: Option<ObjectId>,
+    /// The other player of a transfer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) counterparty_id: Option<ObjectId>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) reason: Option<String>,
+    pub(crate) created_at: DateTime,
+}
+
+/// Multi-document transactions need a replica set or a sharded cluster, which is detected at
+/// startup by [`data_source::detect_transaction_support`]. Without them, changes to a single
+/// player are applied one write after the other and undone if a later write fails, like
+/// registration, credit changes and deleting a game. Changes spanning several players or bets,
+/// like settlements, transfers and bets, can not be undone safely and are refused with this
+/// error instead.
+pub fn require_transactions() -> Result<(), Error> {
+    if data_source::transactions_supported() {
+        return Ok(());
+    }
+
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "This needs a database with transactions, run MongoDB as a replica set",
+    )
+    .into())
+}
+
+/// Whether the error comes from [`require_transactions`].
+pub fn is_transactions_unsupported(e: &Error) -> bool {
+    match e.kind.as_ref() {
+        mongodb::error::ErrorKind::Io(io) => io.kind() == std::io::ErrorKind::Unsupported,
+        _ => false,
+    }
+}
+
+fn ledger_summary(ledger: &Document) -> (i64, bool) {
+    let sum = ledger
+        .get_i64("sum")
+        .or_else(|_| ledger.get_i32("sum").map(i64::from))
+        .unwrap_or(0);
+
+    (sum, ledger.get_bool("opened").unwrap_or(false))
+}
+
+/// Opening balance a player needs so their ledger adds up to `credits`. Only players without a
+/// buy in or opening balance predate the ledger, the others already start at the right balance.
+fn opening_amount(credits: u64, recorded: i64, opened: bool) -> Option<i64> {
+    let amount = credits as i64 - recorded;
+
+    if opened || amount == 0 {
+        None
+    } else {
+        Some(amount)
+    }
+}
+
+impl Transaction {
+    pub fn new(user_id: ObjectId, kind: TransactionKind, amount: i64, balance: i64) -> Self {
+        Transaction {
+            _id: ObjectId::new(),
+            user_id,
+            kind,
+            amount,
+            balance,
+            game_id: None,
+            actor_id: None,
+            counterparty_id: None,
+            reason: None,
+            created_at: DateTime::now(),
+        }
+    }
+
+    pub fn with_game(mut self, game_id: Option<ObjectId>) -> Self {
+        self.game_id = game_id;
+        self
+    }
+
+    pub fn with_actor(mut self, actor_id: Option<ObjectId>) -> Self {
+        self.actor_id = actor_id;
+        self
+    }
+
+    pub fn with_counterparty(mut self, counterparty_id: ObjectId) -> Self {
+        self.counterparty_id = Some(counterparty_id);
+        self
+    }
+
+    pub fn with_reason(mut self, reason: String) -> Self {
+        self.reason = Some(reason);
+        self
+    }
+
+    pub async fn insert(&self, data_source: DataSource) -> Result<ObjectId, Error> {
+        let client = data_source.get_new_db_client().await?;
+        let db = client.database(data_source.database_identifier);
+        let collection: Collection<Transaction> = db.collection(data_source.collection_identifier);
+
+        collection.insert_one(self).await?;
+
+        Ok(self._id)
+    }
+
+    /// Inserts the record as part of the transaction running on the session.
+    pub async fn insert_with_session(
+        &self,
+        session: &mut ClientSession,
+        data_source: DataSource,
+    ) -> Result<ObjectId, Error> {
+        let collection: Collection<Transaction> = session
+            .client()
+            .database(data_source.database_identifier)
+            .collection(data_source.collection_identifier);
+
+        collection.insert_one(self).session(&mut *session).await?;
+
+        Ok(self._id)
+    }
+
+    /// Sum of all credits the player has transferred to others, read as part of the transaction.
+    pub async fn transferred_out(
+        user_id: ObjectId,
+        session: &mut ClientSession,
+        data_source: DataSource,
+    ) -> Result<u64, Error> {
+        let collection: Collection<Transaction> = session
+            .client()
+            .database(data_source.database_identifier)
+            .collection(data_source.collection_identifier);
+
+        let filter = doc! { "user_id": user_id, "kind": TransactionKind::TransferOut.to_string() };
+        let mut cursor = collection.find(filter).session(&mut *session).await?;
+
+        let mut sum = 0;
+        while let Some(t) = cursor.next(&mut *session).await {
+            sum += t?.amount.unsigned_abs();
+        }
+
+        Ok(sum)
+    }
+
+    /// Credit history of a player, newest first.
+    pub async fn get_by_user(
+        user_id: ObjectId,
+        data_source: DataSource,
+    ) -> Result<Vec<Self>, Error> {
+        let client = data_source.get_new_db_client().await?;
+        let db = client.database(data_source.database_identifier);
+        let collection: Collection<Transaction> = db.collection(data_source.collection_identifier);
+
+        let res = collection
+            .find(doc! { "user_id": user_id })
+            .sort(doc! { "created_at": -1 })
+            .await?
+            .try_collect()
+            .await?;
+
+        Ok(res)
+    }
+
+    /// Writes an opening balance for every player that was registered before the ledger, so the
+    /// sum of their ledger matches their credits. Players whose ledger does not add up to their
+    /// credits are logged. Returns how many opening balances were written.
+    pub async fn record_opening_balances(
+        players: Vec<DBUser>,
+        data_source: DataSource,
+    ) -> Result<u64, Error> {
+        let client = data_source.get_new_db_client().await?;
+        let db = client.database(data_source.database_identifier);
+        let collection: Collection<Transaction> = db.collection(data_source.collection_identifier);
+
+        let opening_kinds = [
+            TransactionKind::BuyIn.to_string(),
+            TransactionKind::OpeningBalance.to_string(),
+        ];
+        let pipeline = vec![doc! {
+            "$group": {
+                "_id": "$user_id",
+                "sum": { "$sum": "$amount" },
+                "opened": { "$max": { "$in": ["$kind", opening_kinds.to_vec()] } },
+            },
+        }];
+
+        let mut ledgers: HashMap<ObjectId, (i64, bool)> = HashMap::new();
+        let mut cursor = collection.aggregate(pipeline).await?;
+        while let Some(ledger) = cursor.try_next().await? {
+            if let Ok(user_id) = ledger.get_object_id("_id") {
+                ledgers.insert(user_id, ledger_summary(&ledger));
+            }
+        }
+
+        let mut written = 0;
+        for player in players {
+            let credits = player.credits.unwrap_or(0);
+            let (recorded, opened) = ledgers.get(&player._id).copied().unwrap_or((0, false));
+
+            match opening_amount(credits, recorded, opened) {
+                Some(amount) => {
+                    // Dated to the registration, so it comes before everything recorded since
+                    let mut opening = Transaction::new(
+                        player._id,
+                        TransactionKind::OpeningBalance,
+                        amount,
+                        amount,
+                    );
+                    opening.created_at = player._id.timestamp();
+
+                    collection.insert_one(&opening).await?;
+                    written += 1;
+                }
+                None if opened && recorded != credits as i64 => warn!(
+                    "Ledger of {} adds up to {} but the player has {} credits",
+                    player._id, recorded, credits
+                ),
+                None => {}
+            }
+        }
+
+        Ok(written)
+    }
+
+    pub fn get_json_value(&self) -> serde_json::Value {
+        serde_json::json!({
+            "_id": self._id.to_string(),
+            "user_id": self.user_id.to_string(),
+            "kind": self.kind.to_string(),
+            "amount": self.amount,
+            "balance": self.balance,
+            "game_id": self.game_id.map(|g| g.to_string()),
+            "actor_id": self.actor_id.map(|a| a.to_string()),
+            "counterparty_id": self.counterparty_id.map(|c| c.to_string()),
+            "reason": self.reason,
+            "created_at": self.created_at.try_to_rfc3339_string().unwrap_or_default(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn player_from_before_the_ledger_gets_an_opening_balance() {
+        assert_eq!(opening_amount(500, 0, false), Some(500));
+    }
+
+    #[test]
+    fn opening_balance_covers_changes_recorded_since() {
+        // joined a game for 20 after the ledger was introduced
+        assert_eq!(opening_amount(480, -20, false), Some(500));
+    }
+
+    #[test]
+    fn players_with_a_buy_in_need_no_opening_balance() {
+        assert_eq!(opening_amount(480, 480, true), None);
+        assert_eq!(opening_amount(480, 500, true), None);
+    }
+
+    #[test]
+    fn reconciled_ledger_needs_no_opening_balance() {
+        assert_eq!(opening_amount(0, 0, false), None);
+    }
+
+    #[test]
+    fn ledger_sum_is_read_as_either_integer_width() {
+        assert_eq!(
+            ledger_summary(&doc! { "sum": 7_i32, "opened": true }),
+            (7, true)
+        );
+        assert_eq!(
+            ledger_summary(&doc! { "sum": 7_i64, "opened": false }),
+            (7, false)
+        );
+    }
+}
@@ -1,24 +1,244 @@
 use crate::data_source;
 use crate::data_source::game::Game;
-use crate::data_source::{DBUser, DataSource, GAMES};
+use crate::data_source::transaction::{require_transactions, Transaction, TransactionKind};
+use crate::data_source::{DBUser, DataSource, ACTIVE_USERS, GAMES, PENDING_USERS, TRANSACTIONS};
 use argon2::{Argon2, PasswordHash, PasswordVerifier};
 use futures::TryStreamExt;
-use mongodb::bson::doc;
+use log::{error, warn};
 use mongodb::bson::oid::ObjectId;
-use mongodb::error::Error;
-use mongodb::Collection;
+use mongodb::bson::{doc, DateTime, Document};
+use mongodb::error::{Error, WriteFailure};
+use mongodb::options::ReturnDocument;
+use mongodb::{Client, ClientSession, Collection};
+use rand::Rng;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use std::env;
 use std::io::ErrorKind;
 
+const PIN_RETRIES: u32 = 10;
+const DEFAULT_PIN_LENGTH: usize = 6;
+const DEFAULT_PENDING_PIN_TTL_HOURS: i64 = 7 * 24;
+const DIGITS: &[u8] = b"0123456789";
+/// Letters and digits that can not be confused with each other when read aloud or printed.
+const UNAMBIGUOUS_ALPHANUMERICS: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+
+/// Format of newly generated player pins, configured through PIN_LENGTH and PIN_ALPHABET.
+/// PIN_ALPHABET is either "digits" (default) or "alphanumeric".
+pub struct PinFormat {
+    length: usize,
+    alphabet: &'static [u8],
+}
+
+impl PinFormat {
+    pub fn from_env() -> Self {
+        let length = env::var("PIN_LENGTH")
+            .ok()
+            .and_then(|l| l.parse::<usize>().ok())
+            .filter(|l| (4..=16).contains(l))
+            .unwrap_or(DEFAULT_PIN_LENGTH);
+
+        let alphabet = match env::var("PIN_ALPHABET").as_deref() {
+            Ok("alphanumeric") => UNAMBIGUOUS_ALPHANUMERICS,
+            _ => DIGITS,
+        };
+
+        PinFormat { length, alphabet }
+    }
+
+    /// Pins never start with '0', clients that send them as JSON numbers would lose it.
+    pub fn generate(&self) -> String {
+        let mut rng = rand::thread_rng();
+        let leading: Vec<u8> = self
+            .alphabet
+            .iter()
+            .copied()
+            .filter(|c| *c != b'0')
+            .collect();
+
+        (0..self.length)
+            .map(|i| {
+                let alphabet = if i == 0 { &leading[..] } else { self.alphabet };
+                alphabet[rng.gen_range(0..alphabet.len())] as char
+            })
+            .collect()
+    }
+}
+
+/// Minutes after joining in which leaving a game refunds the join fee, 0 disables refunds.
+fn leave_refund_grace_minutes() -> i64 {
+    env::var("LEAVE_REFUND_GRACE_MINUTES")
+        .ok()
+        .and_then(|m| m.parse::<i64>().ok())
+        .filter(|m| *m >= 0)
+        .unwrap_or(0)
+}
+
+fn pending_pin_ttl_hours() -> i64 {
+    env::var("PENDING_PIN_TTL_HOURS")
+        .ok()
+        .and_then(|h| h.parse::<i64>().ok())
+        .filter(|h| *h > 0)
+        .unwrap_or(DEFAULT_PENDING_PIN_TTL_HOURS)
+}
+
+/// Filter for a pending player with the pin that has not expired yet. The TTL monitor removes
+/// expired pins only periodically, so the expiry is checked here as well.
+fn unexpired_pin(pin: &str) -> Document {
+    doc! { "pin": pin, "expires_at": { "$gt": DateTime::now() } }
+}
+
+pub(crate) fn is_duplicate_key(e: &Error) -> bool {
+    match e.kind.as_ref() {
+        mongodb::error::ErrorKind::Write(WriteFailure::WriteError(we)) => we.code == 11000,
+        _ => false,
+    }
+}
+
+pub fn is_not_found(e: &Error) -> bool {
+    match e.kind.as_ref() {
+        mongodb::error::ErrorKind::Io(io) => io.kind() == ErrorKind::NotFound,
+        _ => false,
+    }
+}
+
+fn activated(mut user: DBUser, name: &str, nickname: &str) -> DBUser {
+    user.name = Some(name.to_string());
+    user.nickname = Some(nickname.to_string());
+    user.expires_at = None;
+    user
+}
+
+/// Applies `update` to the player matching `filter` and writes the ledger record built from the
+/// player as it was before the update. Both are written in one transaction where supported,
+/// otherwise the update is undone if the record can not be written, see [`require_transactions`].
+/// `record` returns the ledger record together with the update that undoes the change, or `None`
+/// if nothing is recorded.
+/// Returns the player before the update, or `None` if no player matched.
+pub(crate) async fn update_and_record<F>(
+    filter: Document,
+    update: Document,
+    record: F,
+    data: &DataSource,
+) -> Result<Option<DBUser>, Error>
+where
+    F: FnOnce(&DBUser) -> Option<(Transaction, Document)>,
+{
+    let client = data.get_new_db_client().await?;
+    let collection: Collection<DBUser> = client
+        .database(data.database_identifier)
+        .collection(data.collection_identifier);
+
+    if !data_source::transactions_supported() {
+        let before = collection
+            .find_one_and_update(filter, update)
+            .return_document(ReturnDocument::Before)
+            .await?;
+        let before = match before {
+            Some(u) => u,
+            None => return Ok(None),
+        };
+
+        if let Some((transaction, undo)) = record(&before) {
+            if let Err(e) = transaction.insert(TRANSACTIONS).await {
+                if let Err(undo_error) = collection
+                    .update_one(doc! { "_id": before._id }, undo)
+                    .await
+                {
+                    error!(
+                        "Could not undo the unrecorded credit change of {}: {}",
+                        before._id, undo_error
+                    );
+                }
+                return Err(e);
+            }
+        }
+
+        return Ok(Some(before));
+    }
+
+    let mut session = client.start_session().await?;
+    session.start_transaction().await?;
+
+    let before = collection
+        .find_one_and_update(filter, update)
+        .return_document(ReturnDocument::Before)
+        .session(&mut session)
+        .await;
+
+    let res = match before {
+        Ok(Some(before)) => match record(&before) {
+            Some((transaction, _)) => transaction
+                .insert_with_session(&mut session, TRANSACTIONS)
+                .await
+                .map(|_| Some(before)),
+            None => Ok(Some(before)),
+        },
+        Ok(None) => Ok(None),
+        Err(e) => Err(e),
+    };
+
+    match res {
+        Ok(Some(before)) => {
+            session.commit_transaction().await?;
+            Ok(Some(before))
+        }
+        Ok(None) => {
+            session.abort_transaction().await?;
+            Ok(None)
+        }
+        Err(e) => {
+            session.abort_transaction().await?;
+            Err(e)
+        }
+    }
+}
+
+/// Outcome of a transfer between two players.
+pub enum TransferResult {
+    Done {
+        sender: u64,
+        recipient: u64,
+    },
+    InsufficientCredits,
+    /// The transfer would exceed the cap, holds how much the sender may still transfer.
+    CapExceeded(u64),
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Player {
     pub(crate) name: Option<String>,
     pub(crate) nickname: Option<String>,
     pub(crate) _id: ObjectId,
     pub(crate) credits: u64,
-    pub(crate) pin: u32,
+    pub(crate) pin: String,
     pub(crate) active_game: Option<ObjectId>,
+    pub(crate) gameday_id: Option<ObjectId>,
+    pub(crate) created_at: Option<DateTime>,
+    pub(crate) expires_at: Option<DateTime>,
+    /// When the player sat down at `active_game`.
+    pub(crate) joined_at: Option<DateTime>,
+}
+
+impl Player {
+    /// A not yet registered player, redeemable by its pin until it expires.
+    pub fn new_pending(credits: u64, gameday_id: Option<ObjectId>, pin_format: &PinFormat) -> Self {
+        let now = DateTime::now();
+        let lifetime_ms = pending_pin_ttl_hours() * 60 * 60 * 1000;
+
+        Player {
+            name: None,
+            nickname: None,
+            _id: ObjectId::new(),
+            credits,
+            pin: pin_format.generate(),
+            active_game: None,
+            gameday_id,
+            created_at: Some(now),
+            expires_at: Some(DateTime::from_millis(now.timestamp_millis() + lifetime_ms)),
+            joined_at: None,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -26,6 +246,8 @@ pub struct Dealer {
     pub(crate) name: String,
     pub(crate) _id: ObjectId,
     pub(crate) password: String,
+    pub(crate) role: data_source::Roles,
+    pub(crate) disabled: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -35,19 +257,104 @@ pub enum User {
 }
 
 impl User {
-    pub async fn new(data: User, data_source: DataSource) -> Result<ObjectId, Error> {
-        let insert_doc: data_source::DBUser = data.into();
+    /// Inserts the user and returns it as stored.
+    /// Players get a fresh pin whenever theirs is already in use by a pending or active player.
+    pub async fn new(data: User, data_source: DataSource) -> Result<User, Error> {
+        let mut insert_doc: data_source::DBUser = data.into();
+        let is_player = insert_doc.role == data_source::Roles::Player;
 
         let client = data_source.get_new_db_client().await?;
         let db = client.database(data_source.database_identifier);
         let coll: Collection<data_source::DBUser> =
-            db.collection(data_source.collection_identifier.into());
+            db.collection(data_source.collection_identifier);
+
+        let pin_format = PinFormat::from_env();
 
-        let res = coll.insert_one(&insert_doc).await?;
+        for _ in 0..PIN_RETRIES {
+            if is_player {
+                let pin = insert_doc
+                    .pin
+                    .get_or_insert_with(|| pin_format.generate())
+                    .clone();
+
+                if Self::is_pin_taken(&pin, &client).await? {
+                    insert_doc.pin = Some(pin_format.generate());
+                    continue;
+                }
+            }
+
+            match coll.insert_one(&insert_doc).await {
+                Ok(_) => return Ok(insert_doc.into()),
+                Err(e) if is_player && is_duplicate_key(&e) => {
+                    insert_doc.pin = Some(pin_format.generate());
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        Err(std::io::Error::new(ErrorKind::AlreadyExists, "Could not find an unused pin").into())
+    }
+
+    /// Checks both pending and active players, a pin has to be unique across both.
+    async fn is_pin_taken(pin: &str, client: &Client) -> Result<bool, Error> {
+        for source in [PENDING_USERS, ACTIVE_USERS] {
+            let coll: Collection<Document> = client
+                .database(source.database_identifier)
+                .collection(source.collection_identifier);
+
+            let count = coll.count_documents(doc! { "pin": pin }).limit(1).await?;
+
+            if count > 0 {
+                return Ok(true);
+            }
+        }
 
-        res.inserted_id
-            .as_object_id()
-            .ok_or_else(|| Error::from(ErrorKind::InvalidData))
+        Ok(false)
+    }
+
+    /// Rewrites pins that were stored as numbers to strings, so lookups by pin match them.
+    /// Dealers used to be stored with a pin of 0, which is removed. Returns the converted pins.
+    pub async fn migrate_numeric_pins(data_source: DataSource) -> Result<u64, Error> {
+        let client = data_source.get_new_db_client().await?;
+        let db = client.database(data_source.database_identifier);
+        let coll: Collection<Document> = db.collection(data_source.collection_identifier);
+
+        let player = data_source::Roles::Player.to_string();
+        coll.update_many(
+            doc! { "pin": 0, "role": { "$ne": &player } },
+            doc! { "$unset": { "pin": "" } },
+        )
+        .await?;
+
+        let res = coll
+            .update_many(
+                doc! { "pin": { "$type": "number" } },
+                vec![doc! { "$set": { "pin": { "$toString": { "$toLong": "$pin" } } } }],
+            )
+            .await?;
+
+        Ok(res.modified_count)
+    }
+
+    /// Pending players created before pins expired have no expiry and would never be removed.
+    /// Their expiry is set from the creation time in the `_id`, so old pins expire right away.
+    /// Returns the updated pending players.
+    pub async fn backfill_pending_expiry(data_source: DataSource) -> Result<u64, Error> {
+        let client = data_source.get_new_db_client().await?;
+        let db = client.database(data_source.database_identifier);
+        let coll: Collection<Document> = db.collection(data_source.collection_identifier);
+
+        let lifetime_ms = pending_pin_ttl_hours() * 60 * 60 * 1000;
+        let res = coll
+            .update_many(
+                doc! { "expires_at": { "$exists": false } },
+                vec![doc! {
+                    "$set": { "expires_at": { "$add": [{ "$toDate": "$_id" }, lifetime_ms] } },
+                }],
+            )
+            .await?;
+
+        Ok(res.modified_count)
     }
 
     pub async fn get(_id: ObjectId, data_source: DataSource) -> Result<Option<User>, Error> {
@@ -55,7 +362,7 @@ impl User {
 
         let db = client.database(data_source.database_identifier);
         let collection: Collection<data_source::DBUser> =
-            db.collection(&*data_source.collection_identifier.to_string());
+            db.collection(data_source.collection_identifier);
 
         let filter = doc! { "_id": _id };
         let res = collection.find_one(filter).await;
@@ -70,17 +377,22 @@ impl User {
         }
     }
 
+    /// Users with the role, players can be narrowed down to a gameday.
     pub async fn get_by_role(
         role: data_source::Roles,
+        gameday_id: Option<ObjectId>,
         data_source: DataSource,
     ) -> Result<Vec<DBUser>, Error> {
         let client = data_source.get_new_db_client().await?;
 
         let db = client.database(data_source.database_identifier);
         let collection: Collection<data_source::DBUser> =
-            db.collection(&*data_source.collection_identifier.to_string());
+            db.collection(data_source.collection_identifier);
 
-        let filter = doc! { "role": role.to_string() };
+        let mut filter = doc! { "role": role.to_string() };
+        if let Some(gameday_id) = gameday_id {
+            filter.insert("gameday_id", gameday_id);
+        }
         let res: Vec<DBUser> = collection.find(filter).await?.try_collect().await?;
 
         Ok(res)
@@ -105,12 +417,128 @@ impl User {
         }
     }
 
+    /// Deletes a dealer, cashier or admin. Players can not be deleted through this.
+    pub async fn delete_staff(_id: ObjectId, data_source: DataSource) -> Result<bool, Error> {
+        let client = data_source.get_new_db_client().await?;
+        let db = client.database(data_source.database_identifier);
+        let coll: Collection<DBUser> = db.collection(data_source.collection_identifier);
+
+        let filter = doc! {
+            "_id": _id,
+            "role": { "$ne": data_source::Roles::Player.to_string() },
+        };
+        let res = coll.delete_one(filter).await?;
+
+        Ok(res.deleted_count == 1)
+    }
+
+    /// Registered players matching the given name and/or nickname.
+    pub async fn find_players(
+        name: Option<&str>,
+        nickname: Option<&str>,
+        gameday_id: Option<ObjectId>,
+        data_source: DataSource,
+    ) -> Result<Vec<Player>, Error> {
+        let client = data_source.get_new_db_client().await?;
+        let db = client.database(data_source.database_identifier);
+        let coll: Collection<DBUser> = db.collection(data_source.collection_identifier);
+
+        let mut filter = doc! { "role": data_source::Roles::Player.to_string() };
+        if let Some(gameday_id) = gameday_id {
+            filter.insert("gameday_id", gameday_id);
+        }
+        if let Some(name) = name {
+            filter.insert("name", name);
+        }
+        if let Some(nickname) = nickname {
+            filter.insert("nickname", nickname);
+        }
+
+        let res: Vec<DBUser> = coll.find(filter).await?.try_collect().await?;
+
+        Ok(res.into_iter().map(DBUser::into).collect())
+    }
+
+    /// Replaces the pin of a registered player with a new unique one, the old pin stops working.
+    pub async fn reissue_pin(
+        user_id: ObjectId,
+        data_source: DataSource,
+    ) -> Result<Option<String>, Error> {
+        let client = data_source.get_new_db_client().await?;
+        let db = client.database(data_source.database_identifier);
+        let coll: Collection<DBUser> = db.collection(data_source.collection_identifier);
+
+        let pin_format = PinFormat::from_env();
+        let filter = doc! {
+            "_id": user_id,
+            "role": data_source::Roles::Player.to_string(),
+        };
+
+        for _ in 0..PIN_RETRIES {
+            let pin = pin_format.generate();
+
+            if Self::is_pin_taken(&pin, &client).await? {
+                continue;
+            }
+
+            match coll
+                .update_one(filter.clone(), doc! { "$set": { "pin": &pin } })
+                .await
+            {
+                Ok(res) if res.matched_count == 0 => return Ok(None),
+                Ok(_) => return Ok(Some(pin)),
+                Err(e) if is_duplicate_key(&e) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Err(std::io::Error::new(ErrorKind::AlreadyExists, "Could not find an unused pin").into())
+    }
+
+    /// Unused, not yet expired pins of a gameday.
+    pub async fn get_pending(
+        gameday_id: ObjectId,
+        data_source: DataSource,
+    ) -> Result<Vec<Player>, Error> {
+        let client = data_source.get_new_db_client().await?;
+        let db = client.database(data_source.database_identifier);
+        let coll: Collection<DBUser> = db.collection(data_source.collection_identifier);
+
+        let filter = doc! {
+            "gameday_id": gameday_id,
+            "expires_at": { "$gt": DateTime::now() },
+        };
+        let res: Vec<DBUser> = coll.find(filter).await?.try_collect().await?;
+
+        Ok(res.into_iter().map(DBUser::into).collect())
+    }
+
+    /// Deletes one or, without a pin, all pending pins of a gameday.
+    pub async fn revoke_pending(
+        gameday_id: ObjectId,
+        pin: Option<&str>,
+        data_source: DataSource,
+    ) -> Result<u64, Error> {
+        let client = data_source.get_new_db_client().await?;
+        let db = client.database(data_source.database_identifier);
+        let coll: Collection<DBUser> = db.collection(data_source.collection_identifier);
+
+        let mut filter = doc! { "gameday_id": gameday_id };
+        if let Some(pin) = pin {
+            filter.insert("pin", pin);
+        }
+
+        let res = coll.delete_many(filter).await?;
+
+        Ok(res.deleted_count)
+    }
+
     pub async fn get_by_name(name: &str, data_source: DataSource) -> Result<Option<User>, Error> {
         let client = data_source.get_new_db_client().await?;
 
         let db = client.database(data_source.database_identifier);
         let collection: Collection<data_source::DBUser> =
-            db.collection(&*data_source.collection_identifier.to_string());
+            db.collection(data_source.collection_identifier);
 
         let filter = doc! { "name": name };
         let res = collection.find_one(filter).await;
@@ -125,79 +553,463 @@ impl User {
         }
     }
 
+    /// Seats the player at the game. The join fee is charged in the same update, which only
+    /// applies if the player can afford it. Returns `false` if the player has insufficient credits.
     pub async fn join_game(
         user_id: ObjectId,
-        game_id: Option<ObjectId>,
-        pin: i64,
+        game_id: ObjectId,
         user_data_source: DataSource,
     ) -> Result<bool, Error> {
-        let client = user_data_source.get_new_db_client().await?;
+        let mut filter = doc! {
+          "_id": &user_id,
+        };
+
+        let game_to_join = Game::get(&game_id, GAMES).await?;
+        let game_to_join = match game_to_join {
+            None => {
+                return Err(Error::from(ErrorKind::InvalidData));
+            }
+            Some(g) => g,
+        };
 
-        let db = client.database(user_data_source.database_identifier);
-        let collection: Collection<User> =
-            db.collection(&*user_data_source.collection_identifier.to_string());
+        let join_fee = i64::from(game_to_join.join_fee);
 
-        let filter = doc! {
-          "_id": &user_id,
-          "pin": &pin,
+        if join_fee != 0 {
+            filter.insert("credits", doc! { "$gte": join_fee });
+        }
+
+        let modify = doc! {
+            "$set": { "active_game": &game_id, "joined_at": DateTime::now() },
+            "$inc": { "credits": -join_fee },
         };
 
-        let join_fee = if game_id.is_some() {
-            let game_to_join = Game::get(
-                &game_id.expect("game id should be checked to be some"),
-                GAMES,
-            )
-            .await?;
-            let game_to_join = match game_to_join {
-                None => {
-                    return Err(Error::from(ErrorKind::InvalidData));
+        let res = update_and_record(
+            filter,
+            modify,
+            |before| {
+                if join_fee == 0 {
+                    return None;
                 }
-                Some(g) => g,
+
+                let balance = before.credits.unwrap_or(0) as i64 - join_fee;
+                let transaction =
+                    Transaction::new(user_id, TransactionKind::JoinFee, -join_fee, balance)
+                        .with_game(Some(game_id));
+                let undo = doc! {
+                    "$set": { "active_game": before.active_game, "joined_at": before.joined_at },
+                    "$inc": { "credits": join_fee },
+                };
+                Some((transaction, undo))
+            },
+            &user_data_source,
+        )
+        .await?;
+
+        if res.is_none() {
+            let exists = User::get(user_id, user_data_source).await?;
+
+            return match exists {
+                None => Err(Error::from(ErrorKind::NotFound)),
+                Some(_) => Ok(false),
             };
+        }
 
-            let join_fee: i32 = game_to_join.join_fee as i32;
-            let join_fee = -join_fee;
+        Ok(true)
+    }
 
-            join_fee
-        } else {
-            0
+    /// Lets the player leave their game. Unless `refund` is unset, the join fee is refunded if
+    /// they leave within LEAVE_REFUND_GRACE_MINUTES of joining. Returns the refunded credits.
+    pub async fn leave_game(
+        user_id: ObjectId,
+        refund: bool,
+        data_source: DataSource,
+    ) -> Result<u64, Error> {
+        let client = data_source.get_new_db_client().await?;
+        let db = client.database(data_source.database_identifier);
+        let collection: Collection<DBUser> = db.collection(data_source.collection_identifier);
+
+        let leave = doc! { "$set": { "active_game": null }, "$unset": { "joined_at": "" } };
+
+        let grace_ms = leave_refund_grace_minutes() * 60 * 1000;
+        let cutoff = DateTime::from_millis(DateTime::now().timestamp_millis() - grace_ms);
+
+        let user = collection.find_one(doc! { "_id": &user_id }).await?;
+        let user = match user {
+            Some(u) => u,
+            None => return Err(Error::from(ErrorKind::NotFound)),
+        };
+
+        let refundable = match (user.active_game, user.joined_at) {
+            (Some(game_id), Some(joined_at)) if refund && grace_ms > 0 && joined_at >= cutoff => {
+                Game::get(&game_id, GAMES).await?.filter(|g| g.join_fee > 0)
+            }
+            _ => None,
         };
 
-        let modify = doc! { "$set": {"active_game": &game_id },"$inc": {"credits": join_fee}  };
+        if let Some(game) = refundable {
+            let fee = i64::from(game.join_fee);
 
-        let res = collection.update_one(filter, modify).await?;
+            // The conditions make sure the fee is refunded only once, even for concurrent requests
+            let filter = doc! {
+                "_id": &user_id,
+                "active_game": game._id,
+                "joined_at": { "$gte": cutoff },
+            };
+            let mut modify = leave.clone();
+            modify.insert("$inc", doc! { "credits": fee });
 
-        match res.matched_count {
-            0 => Err(Error::from(ErrorKind::NotFound)),
-            1 => Ok(true),
-            _ => Ok(false),
+            let res = update_and_record(
+                filter,
+                modify,
+                |before| {
+                    let balance = before.credits.unwrap_or(0) as i64 + fee;
+                    let transaction =
+                        Transaction::new(user_id, TransactionKind::FeeRefund, fee, balance)
+                            .with_game(Some(game._id));
+                    let undo = doc! {
+                        "$set": { "active_game": before.active_game, "joined_at": before.joined_at },
+                        "$inc": { "credits": -fee },
+                    };
+                    Some((transaction, undo))
+                },
+                &data_source,
+            )
+            .await?;
+
+            if res.is_some() {
+                return Ok(fee as u64);
+            }
         }
+
+        collection
+            .update_one(doc! { "_id": &user_id }, leave)
+            .await?;
+
+        Ok(0)
     }
 
+    /// Overwrites the balance of a player and returns the previous one.
     pub async fn set_credits(
         user_id: ObjectId,
         credits: i64,
+        actor_id: ObjectId,
         data: DataSource,
-    ) -> Result<bool, Error> {
-        let client = data.get_new_db_client().await?;
-
-        let db = client.database(data.database_identifier);
-        let collection: Collection<User> = db.collection(&*data.collection_identifier.to_string());
-
+    ) -> Result<u64, Error> {
         let filter = doc! {
           "_id": &user_id,
+          "role": data_source::Roles::Player.to_string(),
         };
         let modify = doc! { "$set": {"credits": credits }  };
 
-        let res = collection.update_one(filter, modify).await?;
+        let res = update_and_record(
+            filter,
+            modify,
+            |before| {
+                let amount = credits - before.credits.unwrap_or(0) as i64;
+                let transaction =
+                    Transaction::new(user_id, TransactionKind::ManualCorrection, amount, credits)
+                        .with_actor(Some(actor_id));
+                Some((transaction, doc! { "$inc": { "credits": -amount } }))
+            },
+            &data,
+        )
+        .await?;
+
+        match res {
+            None => Err(Error::from(ErrorKind::NotFound)),
+            Some(u) => Ok(u.credits.unwrap_or(0)),
+        }
+    }
+
+    /// Adds `amount` to the balance of a player, or subtracts it if negative. The update only
+    /// applies if the balance does not drop below zero, otherwise `None` is returned.
+    pub async fn adjust_credits(
+        user_id: ObjectId,
+        amount: i64,
+        reason: String,
+        actor_id: ObjectId,
+        data: DataSource,
+    ) -> Result<Option<u64>, Error> {
+        let player = data_source::Roles::Player.to_string();
+
+        let mut filter = doc! {
+          "_id": &user_id,
+          "role": &player,
+        };
+        if amount < 0 {
+            filter.insert("credits", doc! { "$gte": -amount });
+        }
+        let modify = doc! { "$inc": {"credits": amount }  };
 
-        match res.matched_count {
+        let kind = if amount < 0 {
+            TransactionKind::Charge
+        } else {
+            TransactionKind::DealerPayout
+        };
+
+        let res = update_and_record(
+            filter,
+            modify,
+            |before| {
+                let balance = before.credits.unwrap_or(0) as i64 + amount;
+                let transaction = Transaction::new(user_id, kind, amount, balance)
+                    .with_game(before.active_game)
+                    .with_actor(Some(actor_id))
+                    .with_reason(reason);
+                Some((transaction, doc! { "$inc": { "credits": -amount } }))
+            },
+            &data,
+        )
+        .await?;
+
+        if let Some(before) = res {
+            return Ok(Some((before.credits.unwrap_or(0) as i64 + amount) as u64));
+        }
+
+        let client = data.get_new_db_client().await?;
+        let collection: Collection<DBUser> = client
+            .database(data.database_identifier)
+            .collection(data.collection_identifier);
+        let exists = collection
+            .count_documents(doc! { "_id": &user_id, "role": &player })
+            .await?;
+
+        match exists {
             0 => Err(Error::from(ErrorKind::NotFound)),
-            1 => Ok(true),
-            _ => Err(Error::from(ErrorKind::Other)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Moves credits from one player to another in a single transaction, nothing is
+    /// applied unless the sender can afford it and stays within `cap`.
+    pub async fn transfer(
+        sender_id: ObjectId,
+        recipient_id: ObjectId,
+        amount: i64,
+        cap: Option<u64>,
+        data: DataSource,
+    ) -> Result<TransferResult, Error> {
+        require_transactions()?;
+
+        let client = data.get_new_db_client().await?;
+        let mut session = client.start_session().await?;
+        session.start_transaction().await?;
+
+        let res =
+            Self::transfer_in_session(sender_id, recipient_id, amount, cap, data, &mut session)
+                .await;
+
+        match res {
+            Ok(TransferResult::Done { sender, recipient }) => {
+                session.commit_transaction().await?;
+                Ok(TransferResult::Done { sender, recipient })
+            }
+            Ok(rejected) => {
+                session.abort_transaction().await?;
+                Ok(rejected)
+            }
+            Err(e) => {
+                session.abort_transaction().await?;
+                Err(e)
+            }
+        }
+    }
+
+    async fn transfer_in_session(
+        sender_id: ObjectId,
+        recipient_id: ObjectId,
+        amount: i64,
+        cap: Option<u64>,
+        data: DataSource,
+        session: &mut ClientSession,
+    ) -> Result<TransferResult, Error> {
+        let collection: Collection<DBUser> = session
+            .client()
+            .database(data.database_identifier)
+            .collection(data.collection_identifier);
+
+        if let Some(cap) = cap {
+            let sent = Transaction::transferred_out(sender_id, session, TRANSACTIONS).await?;
+            let remaining = cap.saturating_sub(sent);
+            if amount as u64 > remaining {
+                return Ok(TransferResult::CapExceeded(remaining));
+            }
+        }
+
+        let player = data_source::Roles::Player.to_string();
+
+        let sender = collection
+            .find_one_and_update(
+                doc! { "_id": sender_id, "role": &player, "credits": { "$gte": amount } },
+                doc! { "$inc": { "credits": -amount } },
+            )
+            .return_document(ReturnDocument::After)
+            .session(&mut *session)
+            .await?;
+        let sender = match sender {
+            Some(u) => u.credits.unwrap_or(0),
+            None => return Ok(TransferResult::InsufficientCredits),
+        };
+
+        let recipient = collection
+            .find_one_and_update(
+                doc! { "_id": recipient_id, "role": &player },
+                doc! { "$inc": { "credits": amount } },
+            )
+            .return_document(ReturnDocument::After)
+            .session(&mut *session)
+            .await?;
+        let recipient = match recipient {
+            Some(u) => u.credits.unwrap_or(0),
+            None => return Err(Error::from(ErrorKind::NotFound)),
+        };
+
+        Transaction::new(
+            sender_id,
+            TransactionKind::TransferOut,
+            -amount,
+            sender as i64,
+        )
+        .with_counterparty(recipient_id)
+        .insert_with_session(session, TRANSACTIONS)
+        .await?;
+        Transaction::new(
+            recipient_id,
+            TransactionKind::TransferIn,
+            amount,
+            recipient as i64,
+        )
+        .with_counterparty(sender_id)
+        .insert_with_session(session, TRANSACTIONS)
+        .await?;
+
+        Ok(TransferResult::Done { sender, recipient })
+    }
+
+    /// Moves the pending player with the pin to the active players and records the buy in.
+    /// Returns `None` if no pending player has the pin.
+    pub async fn activate(
+        pin: &str,
+        name: &str,
+        nickname: &str,
+        pending: DataSource,
+        active: DataSource,
+    ) -> Result<Option<ObjectId>, Error> {
+        if data_source::transactions_supported() {
+            Self::activate_in_transaction(pin, name, nickname, pending, active).await
+        } else {
+            Self::activate_with_recovery(pin, name, nickname, pending, active).await
         }
     }
 
+    async fn activate_in_transaction(
+        pin: &str,
+        name: &str,
+        nickname: &str,
+        pending: DataSource,
+        active: DataSource,
+    ) -> Result<Option<ObjectId>, Error> {
+        let client = active.get_new_db_client().await?;
+        let pending_coll: Collection<DBUser> = client
+            .database(pending.database_identifier)
+            .collection(pending.collection_identifier);
+        let active_coll: Collection<DBUser> = client
+            .database(active.database_identifier)
+            .collection(active.collection_identifier);
+
+        let mut session = client.start_session().await?;
+        session.start_transaction().await?;
+
+        let user = pending_coll
+            .find_one_and_delete(unexpired_pin(pin))
+            .session(&mut session)
+            .await;
+        let user = match user {
+            Ok(Some(u)) => activated(u, name, nickname),
+            Ok(None) => {
+                session.abort_transaction().await?;
+                return Ok(None);
+            }
+            Err(e) => {
+                session.abort_transaction().await?;
+                return Err(e);
+            }
+        };
+
+        let credits = user.credits.unwrap_or(0) as i64;
+        let buy_in = Transaction::new(user._id, TransactionKind::BuyIn, credits, credits);
+
+        let res = match active_coll.insert_one(&user).session(&mut session).await {
+            Ok(_) => buy_in.insert_with_session(&mut session, TRANSACTIONS).await,
+            Err(e) => Err(e),
+        };
+
+        match res {
+            Ok(_) => {
+                session.commit_transaction().await?;
+                Ok(Some(user._id))
+            }
+            Err(e) => {
+                session.abort_transaction().await?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Undo fallback described at [`require_transactions`]. If the player can not be activated
+    /// it is put back into the pending players, so the pin is not lost.
+    async fn activate_with_recovery(
+        pin: &str,
+        name: &str,
+        nickname: &str,
+        pending: DataSource,
+        active: DataSource,
+    ) -> Result<Option<ObjectId>, Error> {
+        let client = active.get_new_db_client().await?;
+        let pending_coll: Collection<DBUser> = client
+            .database(pending.database_identifier)
+            .collection(pending.collection_identifier);
+        let active_coll: Collection<DBUser> = client
+            .database(active.database_identifier)
+            .collection(active.collection_identifier);
+
+        let pending_user = match pending_coll.find_one_and_delete(unexpired_pin(pin)).await? {
+            Some(u) => u,
+            None => return Ok(None),
+        };
+
+        let user = activated(pending_user.clone(), name, nickname);
+
+        if let Err(e) = active_coll.insert_one(&user).await {
+            if let Err(restore_error) = pending_coll.insert_one(&pending_user).await {
+                error!(
+                    "Could not restore pending player {} after failed activation: {}",
+                    pending_user._id, restore_error
+                );
+            }
+            return Err(e);
+        }
+
+        let credits = user.credits.unwrap_or(0) as i64;
+        let buy_in = Transaction::new(user._id, TransactionKind::BuyIn, credits, credits);
+        if let Err(e) = buy_in.insert(TRANSACTIONS).await {
+            warn!("Could not record buy in of {}: {}", user._id, e);
+        }
+
+        Ok(Some(user._id))
+    }
+
+    pub async fn get_by_pin(pin: &str, data_source: DataSource) -> Result<Option<Player>, Error> {
+        let client = data_source.get_new_db_client().await?;
+        let db = client.database(data_source.database_identifier);
+        let collection: Collection<DBUser> = db.collection(data_source.collection_identifier);
+
+        let filter = doc! { "pin": pin, "role": data_source::Roles::Player.to_string() };
+        let res = collection.find_one(filter).await?;
+
+        Ok(res.map(DBUser::into))
+    }
+
     pub fn get_json_value(&self) -> serde_json::Value {
         match self {
             User::Player(u) => {
@@ -211,7 +1023,8 @@ impl User {
                     "active_game": match u.active_game {
                       None => {"".to_string()}
                       Some(g) => {g.to_string()}
-                    }
+                    },
+                    "gameday_id": u.gameday_id.map(|g| g.to_string()),
                   }
                 )
             }
@@ -220,7 +1033,8 @@ impl User {
                   {
                     "_id": d._id.to_string(),
                     "name": d.name,
-                    "role": "dealer",
+                    "role": d.role.to_string().to_lowercase(),
+                    "disabled": d.disabled,
                   }
                 )
             }
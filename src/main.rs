@@ -1,10 +1,22 @@
 mod api;
 mod data_source;
 mod mongo_database_connector;
-
+mod pin_sheet;
+mod qr;
+
+use crate::data_source::audit_log::{AuditAction, AuditEntry, AuditFilter};
+use crate::data_source::bet::{is_payout_too_large, Bet};
+use crate::data_source::idempotency::IdempotencyRecord;
+use crate::data_source::login_attempt::{AttemptKey, LoginAttempt};
+use crate::data_source::session::{dealer_session_hours, player_session_hours, Session};
+use crate::data_source::standings::Standings;
+use crate::data_source::transaction::{is_transactions_unsupported, Transaction};
 use crate::data_source::user::Dealer;
-use crate::data_source::{DBUser, ACTIVE_USERS, GAMEDAYS, GAMES, PENDING_USERS};
-use actix_web::http::header::HeaderValue;
+use crate::data_source::{
+    DBUser, Permission, Roles, ACTIVE_USERS, AUDIT_LOG, BETS, GAMEDAYS, GAMEDAY_TEMPLATES, GAMES,
+    IDEMPOTENCY_KEYS, LOGIN_ATTEMPTS, PENDING_USERS, SESSIONS, STANDINGS, TRANSACTIONS,
+};
+use actix_web::http::{header, StatusCode};
 use actix_web::middleware::Logger;
 use actix_web::{
     delete, get, patch, post, web, App, HttpRequest, HttpResponse, HttpServer, Responder,
@@ -12,24 +24,37 @@ use actix_web::{
 use argon2::password_hash::rand_core::OsRng;
 use argon2::password_hash::SaltString;
 use argon2::{Argon2, PasswordHasher};
-use data_source::game::Game;
-use data_source::gameday::Gameday;
-use data_source::user::{Player, User};
-use log::info;
-use mongodb::bson::doc;
+use data_source::game::{Game, Settlement};
+use data_source::gameday::{Gameday, GamedayStatus};
+use data_source::gameday_template::GamedayTemplate;
+use data_source::user::{is_not_found, PinFormat, Player, TransferResult, User};
+use futures::TryStreamExt;
+use log::{error, info, warn};
 use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{bson, doc, to_bson, Bson, DateTime};
+use mongodb::options::IndexOptions;
 use mongodb::{Collection, IndexModel};
+use qr::QrFormat;
 use rand::Rng;
 use serde::Deserialize;
 use serde_json::{json, Value};
+use std::collections::HashMap;
 use std::fs::File;
+use std::future::Future;
 use std::io::Write;
+use std::net::IpAddr;
+use std::time::Duration;
 use std::{env, io};
 
 const DATABASE_IDENT: &str = "viva_las_vegas";
 
 #[post("/gameday")]
-async fn create_gameday(body: web::Json<data_source::Gameday>) -> impl Responder {
+async fn create_gameday(body: web::Json<data_source::Gameday>, req: HttpRequest) -> impl Responder {
+    let dealer = match is_user_authorized(&req, Permission::ManageGamedays).await {
+        Ok(d) => d,
+        Err(e) => return e,
+    };
+
     let client = GAMEDAYS.get_new_db_client().await;
 
     let client = match client {
@@ -42,10 +67,29 @@ async fn create_gameday(body: web::Json<data_source::Gameday>) -> impl Responder
     let db = client.database(DATABASE_IDENT);
     let collection: Collection<Gameday> = db.collection(GAMEDAYS.collection_identifier);
 
-    let res = Gameday::new(body.initial_player_credits, body.name.clone(), &collection).await;
+    let res = Gameday::new(
+        body.initial_player_credits,
+        body.name.clone(),
+        body.transfer_cap,
+        &collection,
+    )
+    .await;
 
     match res {
         Ok(oid) => {
+            audit(
+                AuditEntry::new(&dealer, AuditAction::CreateGameday, Some(oid.to_string()))
+                    .with_change(
+                        None,
+                        Some(bson!({
+                            "name": body.name.as_str(),
+                            "initial_player_credits": body.initial_player_credits as i64,
+                            "transfer_cap": body.transfer_cap.map(|c| c as i64),
+                        })),
+                    ),
+            )
+            .await;
+
             let body = json!(
                 {
                     "_id": oid.to_string(),
@@ -59,51 +103,355 @@ async fn create_gameday(body: web::Json<data_source::Gameday>) -> impl Responder
 }
 
 #[get("/gameday")]
-async fn get_gameday() -> impl Responder {
-    let client = GAMEDAYS.get_new_db_client().await;
+async fn get_gamedays() -> impl Responder {
+    let gamedays = match Gameday::get_all(GAMEDAYS).await {
+        Ok(g) => g,
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
 
-    let client = match client {
-        Ok(value) => value,
-        Err(err) => {
-            return HttpResponse::InternalServerError().body(err.to_string());
+    let mut out = vec![];
+    for gameday in gamedays {
+        match gameday_json(gameday).await {
+            Ok(json) => out.push(json),
+            Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
         }
+    }
+
+    HttpResponse::Ok().json(out)
+}
+
+#[get("/gameday/{gameday_id}")]
+async fn get_gameday(path: web::Path<String>) -> impl Responder {
+    let _id = match ObjectId::parse_str(path.as_str()) {
+        Ok(id) => id,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid ID"),
     };
 
-    let db = client.database(DATABASE_IDENT);
-    let collection: Collection<Gameday> = db.collection(GAMEDAYS.collection_identifier);
+    let gameday = match Gameday::get(&_id, GAMEDAYS).await {
+        Ok(Some(g)) => g,
+        Ok(None) => return HttpResponse::NotFound().body("Gameday not found"),
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+
+    match gameday_json(gameday).await {
+        Ok(json) => HttpResponse::Ok().json(json),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[get("/gameday/{gameday_id}/standings")]
+async fn get_standings(path: web::Path<String>) -> impl Responder {
+    let _id = match ObjectId::parse_str(path.as_str()) {
+        Ok(id) => id,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid ID"),
+    };
+
+    match Standings::get(&_id, STANDINGS).await {
+        Ok(Some(standings)) => HttpResponse::Ok().json(standings.get_json_value()),
+        Ok(None) => HttpResponse::NotFound().body("Standings not found"),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[derive(Deserialize)]
+struct CreateTemplateBody {
+    name: String,
+}
+
+#[post("/gameday/{gameday_id}/template")]
+async fn create_gameday_template(
+    path: web::Path<String>,
+    body: web::Json<CreateTemplateBody>,
+    req: HttpRequest,
+) -> impl Responder {
+    let admin = match is_user_authorized(&req, Permission::ManageGamedays).await {
+        Ok(a) => a,
+        Err(e) => return e,
+    };
+
+    let gameday_id = match ObjectId::parse_str(path.as_str()) {
+        Ok(id) => id,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid ID"),
+    };
+
+    let gameday = match Gameday::get(&gameday_id, GAMEDAYS).await {
+        Ok(Some(g)) => g,
+        Ok(None) => return HttpResponse::NotFound().body("Gameday not found"),
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+
+    let games = match Game::get_all(Some(gameday_id), GAMES).await {
+        Ok(g) => g,
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+
+    let res =
+        GamedayTemplate::from_gameday(body.name.clone(), &gameday, games, GAMEDAY_TEMPLATES).await;
 
-    let res = collection.find(Default::default()).await;
     match res {
-        Ok(gameday) => {
-            let gameday = gameday.deserialize_current();
-            let gameday = match gameday {
-                Ok(g) => g,
-                Err(_) => {
-                    return HttpResponse::InternalServerError().body("gameday not found");
+        Ok(template) => {
+            audit(
+                AuditEntry::new(
+                    &admin,
+                    AuditAction::CreateTemplate,
+                    Some(template._id.to_string()),
+                )
+                .with_change(None, Some(bson!({ "gameday_id": gameday_id.to_string() }))),
+            )
+            .await;
+
+            HttpResponse::Ok().json(template.get_json_value())
+        }
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[get("/gameday_template")]
+async fn get_gameday_templates(req: HttpRequest) -> impl Responder {
+    if let Err(e) = is_user_authorized(&req, Permission::ManageGamedays).await {
+        return e;
+    }
+
+    match GamedayTemplate::get_all(GAMEDAY_TEMPLATES).await {
+        Ok(templates) => {
+            let json: Vec<Value> = templates.iter().map(|t| t.get_json_value()).collect();
+            HttpResponse::Ok().json(json)
+        }
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[derive(Deserialize)]
+struct GamedayFromTemplateBody {
+    name: String,
+    initial_player_credits: Option<u64>,
+    /// Join fees by game name that replace the ones of the template.
+    #[serde(default)]
+    join_fees: HashMap<String, u64>,
+}
+
+#[post("/gameday_template/{template_id}/gameday")]
+async fn create_gameday_from_template(
+    path: web::Path<String>,
+    body: web::Json<GamedayFromTemplateBody>,
+    req: HttpRequest,
+) -> impl Responder {
+    let admin = match is_user_authorized(&req, Permission::ManageGamedays).await {
+        Ok(a) => a,
+        Err(e) => return e,
+    };
+
+    let template_id = match ObjectId::parse_str(path.as_str()) {
+        Ok(id) => id,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid ID"),
+    };
+
+    let mut template = match GamedayTemplate::get(&template_id, GAMEDAY_TEMPLATES).await {
+        Ok(Some(t)) => t,
+        Ok(None) => return HttpResponse::NotFound().body("Template not found"),
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+
+    if let Some(unknown) = body
+        .join_fees
+        .keys()
+        .find(|name| !template.games.iter().any(|g| &g.name == *name))
+    {
+        return HttpResponse::BadRequest().body(format!("Template has no game {}", unknown));
+    }
+
+    let mut games = std::mem::take(&mut template.games);
+    for game in &mut games {
+        if let Some(fee) = body.join_fees.get(&game.name) {
+            game.join_fee = *fee;
+        }
+        if let Err(e) = game.validate() {
+            return HttpResponse::BadRequest().body(e);
+        }
+    }
+
+    let initial_player_credits = body
+        .initial_player_credits
+        .unwrap_or(template.initial_player_credits);
+
+    let res = template
+        .create_gameday(
+            body.name.clone(),
+            initial_player_credits,
+            games,
+            GAMEDAYS,
+            GAMES,
+        )
+        .await;
+
+    let gameday_id = match res {
+        Ok(id) => id,
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+
+    audit(
+        AuditEntry::new(
+            &admin,
+            AuditAction::CreateGameday,
+            Some(gameday_id.to_string()),
+        )
+        .with_change(
+            None,
+            Some(bson!({
+                "name": body.name.as_str(),
+                "initial_player_credits": initial_player_credits as i64,
+                "template_id": template_id.to_string(),
+            })),
+        ),
+    )
+    .await;
+
+    match Gameday::get(&gameday_id, GAMEDAYS).await {
+        Ok(Some(gameday)) => match gameday_json(gameday).await {
+            Ok(json) => HttpResponse::Ok().json(json),
+            Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+        },
+        Ok(None) => HttpResponse::NotFound().body("Gameday not found"),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+/// The gameday together with all of its games.
+async fn gameday_json(mut gameday: Gameday) -> Result<Value, mongodb::error::Error> {
+    gameday.games = Game::get_all(Some(gameday._id), GAMES).await?;
+
+    Ok(json!({
+      "_id": gameday._id.to_string(),
+      "name": gameday.name,
+      "initial_player_credits": gameday.initial_player_credits,
+      "transfer_cap": gameday.transfer_cap,
+      "status": gameday.status.to_string(),
+      "games": gameday.games.iter().map(Game::get_json_value).collect::<Vec<Value>>(),
+    }))
+}
+
+#[derive(Deserialize)]
+struct GamedayStatusBody {
+    status: GamedayStatus,
+}
+
+#[patch("/gameday/{gameday_id}/status")]
+async fn set_gameday_status(
+    path: web::Path<String>,
+    body: web::Json<GamedayStatusBody>,
+    req: HttpRequest,
+) -> impl Responder {
+    let dealer = match is_user_authorized(&req, Permission::PauseGamedays).await {
+        Ok(d) => d,
+        Err(e) => return e,
+    };
+
+    let _id = match ObjectId::parse_str(path.as_str()) {
+        Ok(id) => id,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid ID"),
+    };
+
+    let gameday = match Gameday::get(&_id, GAMEDAYS).await {
+        Ok(Some(g)) => g,
+        Ok(None) => return HttpResponse::NotFound().body("Gameday not found"),
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+
+    // Dealers may pause and resume a running gameday, every other transition is up to admins
+    let pause_or_resume = matches!(
+        (gameday.status, body.status),
+        (GamedayStatus::Open, GamedayStatus::Paused) | (GamedayStatus::Paused, GamedayStatus::Open)
+    );
+    if !pause_or_resume && !dealer.role.has_permission(Permission::ManageGamedays) {
+        return HttpResponse::Forbidden().body(format!(
+            "Role {} is missing permission {:?}",
+            dealer.role,
+            Permission::ManageGamedays
+        ));
+    }
+
+    // Balances are final once closed, bets still open would never be paid out or refunded
+    if body.status == GamedayStatus::Closed {
+        let games = match Game::get_all(Some(_id), GAMES).await {
+            Ok(g) => g,
+            Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+        };
+        let game_ids: Vec<ObjectId> = games.iter().map(|g| g._id).collect();
+
+        match Bet::count_open(&game_ids, BETS).await {
+            Ok(0) => {}
+            Ok(open) => {
+                return HttpResponse::Conflict().body(format!(
+                    "{} open bets have to be resolved or voided before closing",
+                    open
+                ))
+            }
+            Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+        }
+    }
+
+    // Catches up if freezing on close failed, archiving must not lose the final balances
+    if gameday.status == GamedayStatus::Closed && body.status == GamedayStatus::Archived {
+        if let Err(e) = Standings::freeze(&gameday, ACTIVE_USERS, STANDINGS).await {
+            return HttpResponse::InternalServerError()
+                .body(format!("Could not freeze the standings: {}", e));
+        }
+    }
+
+    match Gameday::set_status(&_id, body.status, GAMEDAYS).await {
+        Ok(Some(previous)) => {
+            if body.status == GamedayStatus::Closed {
+                if let Err(e) = Standings::freeze(&gameday, ACTIVE_USERS, STANDINGS).await {
+                    warn!("Could not freeze the standings of gameday {}: {}", _id, e);
                 }
-            };
+            }
 
-            let body = json!({
-              "_id": gameday._id.to_string(),
-              "name": gameday.name,
-              "initial_player_credits": gameday.initial_player_credits,
-            });
+            audit(
+                AuditEntry::new(
+                    &dealer,
+                    AuditAction::SetGamedayStatus,
+                    Some(_id.to_string()),
+                )
+                .with_change(
+                    Some(bson!({ "status": previous.to_string() })),
+                    Some(bson!({ "status": body.status.to_string() })),
+                ),
+            )
+            .await;
 
-            HttpResponse::Ok().json(body)
+            HttpResponse::Ok()
+                .json(json!({ "_id": _id.to_string(), "status": body.status.to_string() }))
         }
-        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+        Ok(None) => HttpResponse::Conflict().body(format!(
+            "Gameday can not change from {} to {}",
+            gameday.status, body.status
+        )),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
     }
 }
 
+#[derive(Deserialize)]
+struct PinQrQuery {
+    qr: Option<QrFormat>,
+    #[serde(default)]
+    link: bool,
+}
+
 #[get("/login_pin/{gameday_id}")]
-async fn create_pending_user(path: web::Path<String>, req: HttpRequest) -> impl Responder {
-    let dealer_id = req.headers().get("X-User-Id");
-    let dealer_pw = req.headers().get("X-Dealer-Pw");
+async fn create_pending_user(
+    path: web::Path<String>,
+    query: web::Query<PinQrQuery>,
+    req: HttpRequest,
+) -> impl Responder {
+    let dealer = match is_user_authorized(&req, Permission::CreatePins).await {
+        Ok(d) => d,
+        Err(e) => return e,
+    };
 
-    let auth = is_user_authenticated_dealer(dealer_id, dealer_pw).await;
-    match auth {
-        Ok(_) => {}
-        Err(err) => return err,
+    if query.qr.is_some() && query.link {
+        if let Err(e) = qr::registration_base() {
+            return HttpResponse::InternalServerError().body(e.to_string());
+        }
     }
 
     let client = GAMEDAYS.get_new_db_client().await;
@@ -135,186 +483,403 @@ async fn create_pending_user(path: web::Path<String>, req: HttpRequest) -> impl
         }
     };
 
-    let pin: u32 = rand::thread_rng().gen_range(100_000..999_999);
+    if !gameday.status.allows_new_pins() {
+        return gameday_not_allowed(gameday.status);
+    }
 
-    let data = User::Player(Player {
-        name: None,
-        nickname: None,
-        _id: ObjectId::new(),
-        credits: gameday.initial_player_credits,
-        pin,
-        active_game: None,
-    });
+    let data = User::Player(Player::new_pending(
+        gameday.initial_player_credits,
+        Some(gameday._id),
+        &PinFormat::from_env(),
+    ));
 
     let res = User::new(data, PENDING_USERS).await;
 
     let response: HttpResponse = match res {
-        Ok(_) => {
-            let body = json!({
-                "pin": pin,
+        Ok(User::Player(p)) => {
+            audit(
+                AuditEntry::new(
+                    &dealer,
+                    AuditAction::CreatePins,
+                    Some(gameday_id.to_string()),
+                )
+                .with_change(None, Some(bson!({ "amount": 1 }))),
+            )
+            .await;
+
+            let mut body = json!({
+                "pin": p.pin,
             });
 
+            if let Some(format) = query.qr {
+                let content = if query.link {
+                    qr::registration_link(&p.pin)
+                } else {
+                    Ok(p.pin.clone())
+                };
+
+                match content.and_then(|c| qr::render_data_url(&c, format)) {
+                    Ok(url) => body["qr"] = json!(url),
+                    Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+                }
+            }
+
             HttpResponse::Ok().json(body)
         }
+        Ok(User::Dealer(_)) => HttpResponse::InternalServerError()
+            .body("Recieved User::Dealer wher only User::Player was expected"),
         Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
     };
 
     response
 }
 
-#[post("/register")]
-async fn register_user(body: web::Json<data_source::RegisterUser>) -> impl Responder {
-    let client = PENDING_USERS.get_new_db_client().await;
-    let client = match client {
-        Ok(c) => c,
-        Err(e) => {
-            return HttpResponse::InternalServerError().body(e.to_string());
-        }
-    };
+#[get("/gameday/{gameday_id}/pins")]
+async fn get_pending_pins(path: web::Path<String>, req: HttpRequest) -> impl Responder {
+    let auth = is_user_authorized(&req, Permission::CreatePins).await;
+    match auth {
+        Ok(_) => {}
+        Err(err) => return err,
+    }
 
-    let coll: Collection<data_source::DBUser> = client
-        .database(DATABASE_IDENT)
-        .collection(PENDING_USERS.collection_identifier);
+    let gameday_id = match ObjectId::parse_str(path.as_str()) {
+        Ok(id) => id,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid Gameday ID"),
+    };
 
-    let res = coll.find_one_and_delete(doc! {"pin": body.pin}).await;
+    let res = User::get_pending(gameday_id, PENDING_USERS).await;
 
-    let user: User = match res {
-    Ok(Some(user)) => {
-      user
-    }
-    Err(e) => {
-      return HttpResponse::InternalServerError().body(e.to_string());
+    match res {
+        Ok(players) => HttpResponse::Ok().json(
+            players
+                .iter()
+                .map(|p| {
+                    json!({
+                        "pin": p.pin,
+                        "credits": p.credits,
+                        "created_at": p.created_at.and_then(|d| d.try_to_rfc3339_string().ok()),
+                        "expires_at": p.expires_at.and_then(|d| d.try_to_rfc3339_string().ok()),
+                    })
+                })
+                .collect::<Vec<serde_json::Value>>(),
+        ),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
     }
-    Ok(None) => {
-      let coll: Collection<data_source::DBUser> = client.database(DATABASE_IDENT).collection(ACTIVE_USERS.collection_identifier);
+}
+
+#[delete("/gameday/{gameday_id}/pins")]
+async fn revoke_all_pending_pins(path: web::Path<String>, req: HttpRequest) -> impl Responder {
+    let dealer = match is_user_authorized(&req, Permission::CreatePins).await {
+        Ok(d) => d,
+        Err(e) => return e,
+    };
 
-      let res = coll.find_one(doc! {"pin": body.pin, "name": body.name.as_str(), "nickname": body.nickname.as_str()}).await;
+    let gameday_id = match ObjectId::parse_str(path.as_str()) {
+        Ok(id) => id,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid Gameday ID"),
+    };
 
-      match res {
-        Ok(Some(u)) => {
-          let json = json!({
-            "_id": u._id.to_string(),
-          });
-          return HttpResponse::Ok().json(json);
-        }
-        Err(_) => {
-          return HttpResponse::BadRequest().body("user not found")
-        }
-        _ => {
-          return HttpResponse::BadRequest().body("user not found")
-        }
-      }
+    if let Err(e) = check_gameday(Some(gameday_id), GamedayStatus::allows_changes).await {
+        return e;
     }
-  }.into();
 
-    let client = ACTIVE_USERS.get_new_db_client().await;
-    let client = match client {
-        Ok(c) => c,
-        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
-    };
+    let res = User::revoke_pending(gameday_id, None, PENDING_USERS).await;
 
-    let user = match user {
-        User::Player(mut p) => {
-            p.nickname = Some(body.nickname.clone());
-            p.name = Some(body.name.clone());
+    match res {
+        Ok(count) => {
+            audit(
+                AuditEntry::new(
+                    &dealer,
+                    AuditAction::RevokePins,
+                    Some(gameday_id.to_string()),
+                )
+                .with_change(None, Some(bson!({ "revoked": count as i64 }))),
+            )
+            .await;
 
-            User::Player(p)
-        }
-        User::Dealer(_) => {
-            return HttpResponse::InternalServerError()
-                .body("Recieved User::Dealer wher only User::Player was expected");
+            HttpResponse::Ok().json(json!({ "revoked": count }))
         }
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[delete("/gameday/{gameday_id}/pins/{pin}")]
+async fn revoke_pending_pin(path: web::Path<(String, String)>, req: HttpRequest) -> impl Responder {
+    let dealer = match is_user_authorized(&req, Permission::CreatePins).await {
+        Ok(d) => d,
+        Err(e) => return e,
     };
 
-    let db = client.database(DATABASE_IDENT);
-    let collection: Collection<data_source::DBUser> =
-        db.collection(ACTIVE_USERS.collection_identifier);
+    let (gameday_id, pin) = path.into_inner();
+    let gameday_id = match ObjectId::parse_str(gameday_id.as_str()) {
+        Ok(id) => id,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid Gameday ID"),
+    };
 
-    let user: data_source::DBUser = user.into();
+    if let Err(e) = check_gameday(Some(gameday_id), GamedayStatus::allows_changes).await {
+        return e;
+    }
 
-    let res = collection.insert_one(user).await;
+    let pin = pin.trim().to_uppercase();
+    let res = User::revoke_pending(gameday_id, Some(pin.as_str()), PENDING_USERS).await;
 
     match res {
-        Ok(r) => {
-            let _id = r
-                .inserted_id
-                .as_object_id()
-                .expect("Expect inserted id to be valid ObjectId");
+        Ok(0) => HttpResponse::NotFound().body("Pin not found"),
+        Ok(count) => {
+            audit(
+                AuditEntry::new(
+                    &dealer,
+                    AuditAction::RevokePins,
+                    Some(gameday_id.to_string()),
+                )
+                .with_change(None, Some(bson!({ "revoked": count as i64 }))),
+            )
+            .await;
 
-            let body = json!(
-                {
-                    "_id": _id.to_string(),
-                }
-            );
-            HttpResponse::Ok().json(body)
+            HttpResponse::Ok().body("success".to_string())
         }
         Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
     }
 }
 
-#[post("/game")]
-async fn create_game(body: web::Json<data_source::Game>, req: HttpRequest) -> impl Responder {
-    let dealer_id = req.headers().get("X-User-Id");
-    let dealer_pw = req.headers().get("X-Dealer-Pw");
+#[post("/register")]
+async fn register_user(
+    body: web::Json<data_source::RegisterUser>,
+    req: HttpRequest,
+) -> impl Responder {
+    let ip_key = AttemptKey::Ip(client_ip(&req));
+    let pin_key = AttemptKey::Pin(body.pin.to_string());
 
-    let is_authorized = is_user_authenticated_dealer(dealer_id, dealer_pw).await;
-    match is_authorized {
-        Ok(_) => {}
-        Err(res) => {
-            return res;
+    if let Err(e) = check_lockout(&[&ip_key, &pin_key]).await {
+        return e;
+    }
+
+    match User::get_by_pin(body.pin.as_str(), PENDING_USERS).await {
+        Ok(Some(p)) => {
+            if let Err(e) = check_gameday(p.gameday_id, GamedayStatus::allows_registration).await {
+                return e;
+            }
         }
-    };
+        Ok(None) => {}
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    }
 
-    let res = Game::new(
-        body.join_fee as u32,
-        body.name.clone(),
-        body.description.clone(),
-        body.icon_id.clone(),
-        GAMES,
+    let res = User::activate(
+        body.pin.as_str(),
+        body.name.as_str(),
+        body.nickname.as_str(),
+        PENDING_USERS,
+        ACTIVE_USERS,
     )
     .await;
 
     match res {
-        Ok(game) => {
-            let body = json!(
-                {
-                    "_id": game._id.to_string(),
-                    "join_fee": game.join_fee,
-                    "name": game.name,
+        Ok(Some(_id)) => player_session_response(_id).await,
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+        Ok(None) => {
+            let client = match ACTIVE_USERS.get_new_db_client().await {
+                Ok(c) => c,
+                Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+            };
+
+            let coll: Collection<data_source::DBUser> = client
+                .database(DATABASE_IDENT)
+                .collection(ACTIVE_USERS.collection_identifier);
+
+            let filter = doc! {"pin": body.pin.as_str(), "name": body.name.as_str(), "nickname": body.nickname.as_str()};
+            let res = coll.find_one(filter).await;
+
+            match res {
+                Ok(Some(u)) => {
+                    if let Err(e) = check_lockout(&[&AttemptKey::Player(u._id)]).await {
+                        return e;
+                    }
+
+                    player_session_response(u._id).await
                 }
-            );
+                Ok(None) => {
+                    let filter =
+                        doc! {"name": body.name.as_str(), "nickname": body.nickname.as_str()};
+                    let known_player = coll.find_one(filter).await.ok().flatten();
+
+                    match known_player {
+                        Some(u) => {
+                            let player_key = AttemptKey::Player(u._id);
+                            record_failed_attempt(&[&ip_key, &pin_key, &player_key]).await;
+                        }
+                        None => record_failed_attempt(&[&ip_key, &pin_key]).await,
+                    }
 
-            HttpResponse::Ok().json(body)
+                    HttpResponse::BadRequest().body("user not found")
+                }
+                Err(_) => HttpResponse::BadRequest().body("user not found"),
+            }
         }
-        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
     }
 }
 
-#[get("/game")]
-async fn get_all_games() -> impl Responder {
-    let res = Game::get_all(GAMES).await;
+async fn player_session_response(_id: ObjectId) -> HttpResponse {
+    let session = Session::new(_id, player_session_hours(), SESSIONS).await;
 
-    let games = match res {
-        Ok(g) => g,
-        Err(e) => {
-            return HttpResponse::InternalServerError().body(e.to_string());
+    match session {
+        Ok((session, token)) => HttpResponse::Ok().json(json!({
+            "_id": _id.to_string(),
+            "token": token,
+            "expires_at": session.expires_at.try_to_rfc3339_string().unwrap_or_default(),
+        })),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[post("/logout")]
+async fn logout_player(req: HttpRequest) -> impl Responder {
+    let player = is_user_authenticated_player(&req).await;
+    if let Err(e) = player {
+        return e;
+    }
+
+    let token = match get_bearer_token(&req) {
+        Ok(t) => t,
+        Err(e) => return e,
+    };
+
+    let res = Session::revoke(token, SESSIONS).await;
+
+    match res {
+        Ok(_) => HttpResponse::Ok().body("success".to_string()),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[delete("/user/{user_id}/sessions")]
+async fn revoke_player_sessions(path: web::Path<String>, req: HttpRequest) -> impl Responder {
+    let dealer = match is_user_authorized(&req, Permission::RevokePlayerSessions).await {
+        Ok(d) => d,
+        Err(e) => return e,
+    };
+
+    let id = ObjectId::parse_str(path.as_str());
+    let _id = match id {
+        Ok(id) => id,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid ID"),
+    };
+
+    let user = User::get(_id, ACTIVE_USERS).await;
+    match user {
+        Ok(Some(User::Player(_))) => {}
+        Ok(Some(User::Dealer(_))) => {
+            return HttpResponse::BadRequest().body("User Id does not refer to a player");
+        }
+        Ok(None) => return HttpResponse::NotFound().body("User not found"),
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    }
+
+    let res = Session::revoke_all(_id, SESSIONS).await;
+
+    match res {
+        Ok(count) => {
+            audit(AuditEntry::new(
+                &dealer,
+                AuditAction::RevokeSessions,
+                Some(_id.to_string()),
+            ))
+            .await;
+
+            HttpResponse::Ok().json(json!({ "revoked": count }))
         }
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[post("/game")]
+async fn create_game(body: web::Json<data_source::Game>, req: HttpRequest) -> impl Responder {
+    let dealer = match is_user_authorized(&req, Permission::ManageGames).await {
+        Ok(d) => d,
+        Err(e) => return e,
     };
 
-    let out: Vec<Value> = games
-        .into_iter()
-        .map(|g| {
-            json!(
+    if let Err(e) = body.validate() {
+        return HttpResponse::BadRequest().body(e);
+    }
+
+    let gameday_id = match body.gameday_id {
+        Some(id) => id,
+        None => return HttpResponse::BadRequest().body("Missing gameday_id"),
+    };
+
+    match Gameday::get(&gameday_id, GAMEDAYS).await {
+        Ok(Some(g)) if !g.status.allows_new_games() => return gameday_not_allowed(g.status),
+        Ok(Some(_)) => {}
+        Ok(None) => return HttpResponse::NotFound().body("Gameday not found"),
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    }
+
+    let res = Game::new(
+        body.join_fee as u32,
+        body.name.clone(),
+        body.description.clone(),
+        body.icon_id.clone(),
+        body.payouts.clone(),
+        Some(gameday_id),
+        GAMES,
+    )
+    .await;
+
+    match res {
+        Ok(game) => {
+            audit(
+                AuditEntry::new(&dealer, AuditAction::CreateGame, Some(game._id.to_string()))
+                    .with_change(None, to_bson(&game).ok()),
+            )
+            .await;
+
+            let body = json!(
                 {
-                    "_id": g._id.to_string(),
-                    "join_fee": g.join_fee,
-                    "name": g.name,
-                    "icon_id": g.icon_id.to_string(),
-                    "description": g.description
+                    "_id": game._id.to_string(),
+                    "join_fee": game.join_fee,
+                    "name": game.name,
                 }
-            )
-        })
-        .collect();
+            );
+
+            HttpResponse::Ok().json(body)
+        }
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[derive(Deserialize)]
+struct GamedayFilter {
+    gameday_id: Option<String>,
+}
+
+impl GamedayFilter {
+    fn parse(&self) -> Result<Option<ObjectId>, HttpResponse> {
+        match &self.gameday_id {
+            None => Ok(None),
+            Some(id) => ObjectId::parse_str(id)
+                .map(Some)
+                .map_err(|_| HttpResponse::BadRequest().body("Invalid Gameday ID")),
+        }
+    }
+}
+
+#[get("/game")]
+async fn get_all_games(query: web::Query<GamedayFilter>) -> impl Responder {
+    let gameday_id = match query.parse() {
+        Ok(g) => g,
+        Err(e) => return e,
+    };
+
+    let res = Game::get_all(gameday_id, GAMES).await;
+
+    let games = match res {
+        Ok(g) => g,
+        Err(e) => {
+            return HttpResponse::InternalServerError().body(e.to_string());
+        }
+    };
+
+    let out: Vec<Value> = games.iter().map(Game::get_json_value).collect();
 
     HttpResponse::Ok().json(out)
 }
@@ -341,21 +906,18 @@ async fn get_game(path: web::Path<String>) -> impl Responder {
 
     match res {
         Ok(Some(game)) => {
-            let body = json!(
-                {
-                    "_id": game._id.to_string(),
-                    "join_fee": game.join_fee,
-                    "icon_id": game.icon_id.to_string(),
-                    "name": game.name,
-                    "description": game.description,
-                    "players": users.iter().map(|v| json!({
+            let mut body = game.get_json_value();
+            body["players"] = users
+                .iter()
+                .map(|v| {
+                    json!({
                         "name": v.name,
                         "nickname": v.nickname,
                         "_id": v._id.to_string(),
                         "credits": v.credits,
-                    })).collect::<Vec<serde_json::Value>>(),
-                }
-            );
+                    })
+                })
+                .collect();
 
             HttpResponse::Ok().json(body)
         }
@@ -376,47 +938,59 @@ async fn join_game(path: web::Path<(String, String)>, req: HttpRequest) -> impl
         Err(_) => return HttpResponse::BadRequest().body("Invalid Game ID"),
     };
 
-    let id = req.headers().get("X-User-Id");
-
-    let user_id = match id {
-        None => return HttpResponse::BadRequest().body("User ID not provided in X-User-Id Header"),
-        Some(id) => id,
-    };
-
-    let user_id = match user_id.to_str() {
-        Ok(id) => id,
-        Err(_) => return HttpResponse::BadRequest().body("User ID is not valid utf-8"),
-    };
-
-    let user_id = match ObjectId::parse_str(user_id) {
-        Ok(id) => id,
-        Err(_) => return HttpResponse::BadRequest().body("Invalid USer ID"),
+    let player = match is_user_authenticated_player(&req).await {
+        Ok(p) => p,
+        Err(e) => return e,
     };
 
-    let to_join = match inner_path.1.as_str() {
-        "join" => Some(game_id),
-        "leave" => None,
-        _ => return HttpResponse::BadRequest().body("Invalid Path"),
-    };
+    idempotent(&req, player._id, async move {
+        let res = match inner_path.1.as_str() {
+            "join" => {
+                match Game::get(&game_id, GAMES).await {
+                    Ok(Some(g)) if g.gameday_id.is_some() && g.gameday_id != player.gameday_id => {
+                        return HttpResponse::Forbidden().body("Game belongs to another gameday");
+                    }
+                    Ok(Some(_)) => {}
+                    Ok(None) => return HttpResponse::NotFound().body("Game not found"),
+                    Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+                }
 
-    let pin = req.headers().get("X-User-Pin");
-    let pin = match pin {
-        Some(pin) => match pin.to_str() {
-            Ok(pin) => match pin.parse::<i64>() {
-                Ok(pin) => pin,
-                Err(_) => return HttpResponse::BadRequest().body("Invalid Pin - no number"),
-            },
-            Err(_) => return HttpResponse::BadRequest().body("Invalid Pin - no number"),
-        },
-        None => return HttpResponse::BadRequest().body("No Pin provided in X-User-Pin Header"),
-    };
+                if let Err(e) = check_gameday(player.gameday_id, GamedayStatus::allows_play).await {
+                    return e;
+                }
 
-    let res = User::join_game(user_id, to_join, pin, ACTIVE_USERS).await;
+                User::join_game(player._id, game_id, ACTIVE_USERS).await
+            }
+            "leave" => {
+                // Leaving is possible until the gameday is archived, but the fee is only
+                // refunded while credits may still change
+                let refund = match player.gameday_id {
+                    Some(gameday_id) => match Gameday::get(&gameday_id, GAMEDAYS).await {
+                        Ok(Some(g)) if !g.status.allows_changes() => {
+                            return gameday_not_allowed(g.status)
+                        }
+                        Ok(Some(g)) => g.status.allows_credit_changes(),
+                        Ok(None) => true,
+                        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+                    },
+                    None => true,
+                };
+
+                User::leave_game(player._id, refund, ACTIVE_USERS)
+                    .await
+                    .map(|_| true)
+            }
+            _ => return HttpResponse::BadRequest().body("Invalid Path"),
+        };
 
-    match res {
-        Ok(_) => HttpResponse::Ok().body("success".to_string()),
-        Err(er) => HttpResponse::InternalServerError().body(er.to_string()),
-    }
+        match res {
+            Ok(true) => HttpResponse::Ok().body("success".to_string()),
+            Ok(false) => HttpResponse::PaymentRequired().body("Insufficient credits"),
+            Err(er) if is_not_found(&er) => HttpResponse::NotFound().body("Player not found"),
+            Err(er) => HttpResponse::InternalServerError().body(er.to_string()),
+        }
+    })
+    .await
 }
 
 #[patch("/game/{game_id}")]
@@ -425,16 +999,10 @@ async fn patch_game(
     body: web::Json<data_source::Game>,
     req: HttpRequest,
 ) -> impl Responder {
-    let dealer_id = req.headers().get("X-User-Id");
-    let dealer_pw = req.headers().get("X-Dealer-Pw");
-
-    let auth = is_user_authenticated_dealer(dealer_id, dealer_pw).await;
-    match auth {
-        Ok(_) => {}
-        Err(r) => {
-            return r;
-        }
-    }
+    let dealer = match is_user_authorized(&req, Permission::ManageGames).await {
+        Ok(d) => d,
+        Err(e) => return e,
+    };
 
     let path = path.into_inner();
     let id = ObjectId::parse_str(path.as_str());
@@ -446,286 +1014,1853 @@ async fn patch_game(
 
     let body = body.into_inner();
 
+    if let Err(e) = body.validate() {
+        return HttpResponse::BadRequest().body(e);
+    }
+
+    let mut body = body;
+    let before = match Game::get(&_id, GAMES).await {
+        Ok(Some(game)) => {
+            if let Err(e) = check_gameday(game.gameday_id, GamedayStatus::allows_changes).await {
+                return e;
+            }
+
+            // games stay on the gameday they were created for
+            body.gameday_id = game.gameday_id;
+            to_bson(&game).ok()
+        }
+        Ok(None) => return HttpResponse::NotFound().body("Game not found"),
+        Err(er) => return HttpResponse::InternalServerError().body(er.to_string()),
+    };
+    let after = to_bson(&body).ok();
+
     let res = Game::patch(&_id, GAMES, body).await;
 
     match res {
-        Ok(Some(_)) => HttpResponse::Ok().body("success".to_string()),
+        Ok(Some(_)) => {
+            audit(
+                AuditEntry::new(&dealer, AuditAction::PatchGame, Some(_id.to_string()))
+                    .with_change(before, after),
+            )
+            .await;
+
+            HttpResponse::Ok().body("success".to_string())
+        }
         Ok(None) => HttpResponse::NotFound().body("Game not found"),
         Err(er) => HttpResponse::InternalServerError().body(er.to_string()),
     }
 }
 
-#[delete("/game/{game_id}")]
-async fn delete_game(path: web::Path<String>, req: HttpRequest) -> impl Responder {
-    let dealer_id = req.headers().get("X-User-Id");
-    let dealer_pw = req.headers().get("X-Dealer-Pw");
+#[derive(Deserialize)]
+struct SettleResult {
+    user_id: String,
+    /// Positive for a win, negative for a loss.
+    amount: i64,
+}
 
-    let auth = is_user_authenticated_dealer(dealer_id, dealer_pw).await;
-    match auth {
-        Ok(_) => {}
-        Err(r) => {
-            return r;
-        }
-    }
+#[derive(Deserialize)]
+struct SettleBody {
+    results: Vec<SettleResult>,
+    reason: Option<String>,
+}
 
-    let id = ObjectId::parse_str(path.as_str());
-    let _id = match id {
-        Ok(id) => id,
-        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+#[post("/game/{game_id}/settle")]
+async fn settle_game(
+    path: web::Path<String>,
+    body: web::Json<SettleBody>,
+    req: HttpRequest,
+) -> impl Responder {
+    let dealer = match is_user_authorized(&req, Permission::AdjustCredits).await {
+        Ok(d) => d,
+        Err(e) => return e,
     };
 
-    let res = Game::delete(&_id, GAMES).await;
+    idempotent(&req, dealer._id, async move {
+        let game_id = match ObjectId::parse_str(path.as_str()) {
+            Ok(id) => id,
+            Err(_) => return HttpResponse::BadRequest().body("Invalid Game ID"),
+        };
+
+        let mut results: Vec<(ObjectId, i64)> = vec![];
+        for r in &body.results {
+            let user_id = match ObjectId::parse_str(&r.user_id) {
+                Ok(id) => id,
+                Err(_) => return HttpResponse::BadRequest().body("Invalid User ID"),
+            };
+            if results.iter().any(|(id, _)| *id == user_id) {
+                return HttpResponse::BadRequest()
+                    .body(format!("Player {} is listed twice", user_id));
+            }
+            results.push((user_id, r.amount));
+        }
 
-    match res {
-        Ok(_) => HttpResponse::Ok().body("success".to_string()),
-        Err(er) => HttpResponse::InternalServerError().body(er.to_string()),
-    }
+        let game = match Game::get(&game_id, GAMES).await {
+            Ok(Some(g)) => g,
+            Ok(None) => return HttpResponse::NotFound().body("Game not found"),
+            Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+        };
+
+        if let Err(e) = check_gameday(game.gameday_id, GamedayStatus::allows_credit_changes).await {
+            return e;
+        }
+
+        // Every seated player has to be settled, so nobody is forgotten when the table changed meanwhile
+        let seated = match Game::get_players(&game_id, ACTIVE_USERS).await {
+            Ok(players) => players,
+            Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+        };
+        let all_seated = results
+            .iter()
+            .all(|(id, _)| seated.iter().any(|p| p._id == *id));
+        if !all_seated || seated.len() != results.len() {
+            return HttpResponse::Conflict()
+                .body("Results do not match the players seated at the game");
+        }
+
+        let reason = body
+            .reason
+            .as_deref()
+            .map(str::trim)
+            .filter(|r| !r.is_empty())
+            .unwrap_or("Round settlement");
+
+        let res = Game::settle(&game_id, &results, reason, dealer._id, ACTIVE_USERS).await;
+
+        match res {
+            Ok(Settlement::Settled(balances)) => {
+                let after: Vec<Bson> = results
+                    .iter()
+                    .map(|(id, amount)| bson!({ "user_id": id.to_string(), "amount": amount }))
+                    .collect();
+                audit(
+                    AuditEntry::new(&dealer, AuditAction::SettleRound, Some(game_id.to_string()))
+                        .with_change(None, Some(Bson::Array(after))),
+                )
+                .await;
+
+                let json: Vec<Value> = balances
+                    .iter()
+                    .map(|(id, credits)| json!({ "_id": id.to_string(), "credits": credits }))
+                    .collect();
+                HttpResponse::Ok().json(json)
+            }
+            Ok(Settlement::Rejected(user_id)) => HttpResponse::Conflict().body(format!(
+                "Player {} left the game or has insufficient credits, nothing was settled",
+                user_id
+            )),
+            Err(e) if is_transactions_unsupported(&e) => transactions_required(e),
+            Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+        }
+    })
+    .await
 }
 
 #[derive(Deserialize)]
-struct CreditPatchBody {
-    credits: i64,
+struct PlaceBetBody {
+    outcome: String,
+    stake: u64,
 }
-#[patch("user/{user_id}/credits")]
-pub async fn set_credits(
+
+#[post("/game/{game_id}/bets")]
+async fn place_bet(
     path: web::Path<String>,
-    body: web::Json<CreditPatchBody>,
+    body: web::Json<PlaceBetBody>,
     req: HttpRequest,
 ) -> impl Responder {
-    let dealer_id = req.headers().get("X-User-Id");
-    let dealer_pw = req.headers().get("X-Dealer-Pw");
+    let player = match is_user_authenticated_player(&req).await {
+        Ok(p) => p,
+        Err(e) => return e,
+    };
 
-    let auth = is_user_authenticated_dealer(dealer_id, dealer_pw).await;
-    match auth {
-        Ok(_) => {}
-        Err(e) => {
+    idempotent(&req, player._id, async move {
+        let game_id = match ObjectId::parse_str(path.as_str()) {
+            Ok(id) => id,
+            Err(_) => return HttpResponse::BadRequest().body("Invalid Game ID"),
+        };
+
+        if player.active_game != Some(game_id) {
+            return HttpResponse::Forbidden().body("Player is not seated at this game");
+        }
+
+        if let Err(e) = check_gameday(player.gameday_id, GamedayStatus::allows_play).await {
             return e;
         }
+
+        let game = match Game::get(&game_id, GAMES).await {
+            Ok(Some(g)) => g,
+            Ok(None) => return HttpResponse::NotFound().body("Game not found"),
+            Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+        };
+
+        let outcome = match game.payouts.outcome(&body.outcome) {
+            Some(o) => o,
+            None => return HttpResponse::BadRequest().body("Unknown outcome"),
+        };
+        if !game.payouts.allows_stake(body.stake) || i64::try_from(body.stake).is_err() {
+            return HttpResponse::BadRequest().body("Stake is outside the limits of the game");
+        }
+        if outcome.payout(body.stake).is_none() {
+            return HttpResponse::BadRequest().body("Payout of this stake would be too large");
+        }
+
+        let res = Bet::place(player._id, game_id, outcome, body.stake, BETS).await;
+
+        match res {
+            Ok(Some(bet)) => HttpResponse::Ok().json(bet.get_json_value()),
+            Ok(None) => HttpResponse::PaymentRequired().body("Insufficient credits"),
+            Err(e) if is_transactions_unsupported(&e) => transactions_required(e),
+            Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+        }
+    })
+    .await
+}
+
+#[get("/game/{game_id}/bets")]
+async fn get_open_bets(path: web::Path<String>, req: HttpRequest) -> impl Responder {
+    if let Err(e) = is_user_authenticated_dealer(&req).await {
+        return e;
     }
 
-    let id = ObjectId::parse_str(path.as_str());
-    let _id = match id {
+    let game_id = match ObjectId::parse_str(path.as_str()) {
         Ok(id) => id,
-        Err(e) => {
-            return HttpResponse::InternalServerError().body(e.to_string());
+        Err(_) => return HttpResponse::BadRequest().body("Invalid Game ID"),
+    };
+
+    match Bet::get_open(&game_id, BETS).await {
+        Ok(bets) => {
+            let json: Vec<Value> = bets.iter().map(|b| b.get_json_value()).collect();
+            HttpResponse::Ok().json(json)
         }
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[derive(Deserialize)]
+struct ResolveBetsBody {
+    /// Winning outcome, the round is void if unset.
+    outcome: Option<String>,
+}
+
+#[post("/game/{game_id}/bets/resolve")]
+async fn resolve_bets(
+    path: web::Path<String>,
+    body: web::Json<ResolveBetsBody>,
+    req: HttpRequest,
+) -> impl Responder {
+    let dealer = match is_user_authorized(&req, Permission::AdjustCredits).await {
+        Ok(d) => d,
+        Err(e) => return e,
     };
 
-    let res = User::set_credits(_id, body.credits, ACTIVE_USERS).await;
+    idempotent(&req, dealer._id, async move {
+        let game_id = match ObjectId::parse_str(path.as_str()) {
+            Ok(id) => id,
+            Err(_) => return HttpResponse::BadRequest().body("Invalid Game ID"),
+        };
 
-    match res {
-        Ok(_) => HttpResponse::Ok().body("success".to_string()),
-        Err(er) => HttpResponse::InternalServerError().body(er.to_string()),
-    }
+        let game = match Game::get(&game_id, GAMES).await {
+            Ok(Some(g)) => g,
+            Ok(None) => return HttpResponse::NotFound().body("Game not found"),
+            Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+        };
+
+        if let Err(e) = check_gameday(game.gameday_id, GamedayStatus::allows_credit_changes).await {
+            return e;
+        }
+
+        // Outcomes removed from the game while bets on them are open can still win
+        let winning = body.outcome.as_deref();
+        if let Some(w) = winning {
+            if game.payouts.outcome(w).is_none() {
+                match Bet::get_open(&game_id, BETS).await {
+                    Ok(open) if open.iter().any(|b| b.outcome == w) => {}
+                    Ok(_) => return HttpResponse::BadRequest().body("Unknown outcome"),
+                    Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+                }
+            }
+        }
+
+        let res = Bet::close_round(&game, winning, dealer._id, BETS).await;
+
+        match res {
+            Ok(bets) => {
+                let action = match winning {
+                    Some(_) => AuditAction::ResolveBets,
+                    None => AuditAction::VoidBets,
+                };
+                audit(
+                    AuditEntry::new(&dealer, action, Some(game_id.to_string())).with_change(
+                        None,
+                        Some(bson!({ "outcome": winning, "bets": bets.len() as i64 })),
+                    ),
+                )
+                .await;
+
+                let json: Vec<Value> = bets.iter().map(|b| b.get_json_value()).collect();
+                HttpResponse::Ok().json(json)
+            }
+            Err(e) if is_transactions_unsupported(&e) => transactions_required(e),
+            Err(e) if is_payout_too_large(&e) => HttpResponse::Conflict().body(e.to_string()),
+            Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+        }
+    })
+    .await
 }
 
-#[get("/user/{id}")]
-async fn get_user(path: web::Path<String>) -> impl Responder {
-    let id = path.into_inner();
+#[derive(Deserialize)]
+struct DeleteGameQuery {
+    /// Refunds the join fee to every seated player.
+    refund: Option<bool>,
+}
 
-    match id.as_str() {
-        "player" => get_user_by_role(data_source::Roles::Player).await,
-        "dealer" => get_user_by_role(data_source::Roles::Dealer).await,
-        id => match ObjectId::parse_str(id) {
-            Ok(id) => get_user_by_id(id).await,
-            Err(e) => HttpResponse::NotFound().body(e.to_string()),
-        },
+#[delete("/game/{game_id}")]
+async fn delete_game(
+    path: web::Path<String>,
+    query: web::Query<DeleteGameQuery>,
+    req: HttpRequest,
+) -> impl Responder {
+    let dealer = match is_user_authorized(&req, Permission::ManageGames).await {
+        Ok(d) => d,
+        Err(e) => return e,
+    };
+
+    let id = ObjectId::parse_str(path.as_str());
+    let _id = match id {
+        Ok(id) => id,
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+
+    let game = match Game::get(&_id, GAMES).await {
+        Ok(Some(game)) => game,
+        Ok(None) => return HttpResponse::NotFound().body("Game not found"),
+        Err(er) => return HttpResponse::InternalServerError().body(er.to_string()),
+    };
+    let refund = query.refund.unwrap_or(false);
+    let has_open_bets = match Bet::get_open(&_id, BETS).await {
+        Ok(bets) => !bets.is_empty(),
+        Err(er) => return HttpResponse::InternalServerError().body(er.to_string()),
+    };
+
+    // Refunding fees and voiding bets change credits, which is no longer possible once closed
+    let allows = if refund || has_open_bets {
+        GamedayStatus::allows_credit_changes
+    } else {
+        GamedayStatus::allows_changes
+    };
+    if let Err(e) = check_gameday(game.gameday_id, allows).await {
+        return e;
+    }
+
+    let before = to_bson(&game).ok();
+
+    // Open bets and seated players must not be left pointing at a game that no longer exists
+    let res = game
+        .remove(refund, dealer._id, BETS, ACTIVE_USERS, GAMES)
+        .await;
+
+    match res {
+        Ok((voided, unseated)) => {
+            audit(
+                AuditEntry::new(&dealer, AuditAction::DeleteGame, Some(_id.to_string()))
+                    .with_change(
+                        before,
+                        Some(bson!({
+                            "unseated": unseated as i64,
+                            "refunded": refund,
+                            "voided_bets": voided as i64,
+                        })),
+                    ),
+            )
+            .await;
+
+            HttpResponse::Ok().json(json!({ "unseated": unseated, "voided_bets": voided }))
+        }
+        Err(er) => HttpResponse::InternalServerError().body(er.to_string()),
+    }
+}
+
+#[derive(Deserialize)]
+struct CreditPatchBody {
+    credits: i64,
+}
+#[patch("user/{user_id}/credits")]
+pub async fn set_credits(
+    path: web::Path<String>,
+    body: web::Json<CreditPatchBody>,
+    req: HttpRequest,
+) -> impl Responder {
+    let dealer = match is_user_authorized(&req, Permission::ChangeCredits).await {
+        Ok(d) => d,
+        Err(e) => return e,
+    };
+
+    idempotent(&req, dealer._id, async move {
+        let id = ObjectId::parse_str(path.as_str());
+        let _id = match id {
+            Ok(id) => id,
+            Err(e) => {
+                return HttpResponse::InternalServerError().body(e.to_string());
+            }
+        };
+
+        if body.credits < 0 {
+            return HttpResponse::BadRequest().body("Credits can not be negative");
+        }
+
+        if let Err(e) = check_player_gameday(_id, GamedayStatus::allows_credit_changes).await {
+            return e;
+        }
+
+        let res = User::set_credits(_id, body.credits, dealer._id, ACTIVE_USERS).await;
+
+        match res {
+            Ok(before) => {
+                audit(
+                    AuditEntry::new(&dealer, AuditAction::SetCredits, Some(_id.to_string()))
+                        .with_change(
+                            Some(bson!({ "credits": before as i64 })),
+                            Some(bson!({ "credits": body.credits })),
+                        ),
+                )
+                .await;
+
+                HttpResponse::Ok().body("success".to_string())
+            }
+            Err(er) if is_not_found(&er) => HttpResponse::NotFound().body("Player not found"),
+            Err(er) => HttpResponse::InternalServerError().body(er.to_string()),
+        }
+    })
+    .await
+}
+
+#[derive(Deserialize)]
+struct CreditAdjustBody {
+    /// Positive for a payout, negative for a charge.
+    amount: i64,
+    reason: String,
+}
+
+#[post("/user/{user_id}/credits/adjust")]
+async fn adjust_credits(
+    path: web::Path<String>,
+    body: web::Json<CreditAdjustBody>,
+    req: HttpRequest,
+) -> impl Responder {
+    let dealer = match is_user_authorized(&req, Permission::AdjustCredits).await {
+        Ok(d) => d,
+        Err(e) => return e,
+    };
+
+    idempotent(&req, dealer._id, async move {
+        let _id = match ObjectId::parse_str(path.as_str()) {
+            Ok(id) => id,
+            Err(_) => return HttpResponse::BadRequest().body("Invalid ID"),
+        };
+
+        if body.amount == 0 {
+            return HttpResponse::BadRequest().body("Amount can not be zero");
+        }
+
+        let reason = body.reason.trim();
+        if reason.is_empty() {
+            return HttpResponse::BadRequest().body("A reason is required");
+        }
+
+        if let Err(e) = check_player_gameday(_id, GamedayStatus::allows_credit_changes).await {
+            return e;
+        }
+
+        let res = User::adjust_credits(
+            _id,
+            body.amount,
+            reason.to_string(),
+            dealer._id,
+            ACTIVE_USERS,
+        )
+        .await;
+
+        match res {
+            Ok(Some(balance)) => {
+                audit(
+                    AuditEntry::new(&dealer, AuditAction::AdjustCredits, Some(_id.to_string()))
+                        .with_change(
+                            Some(bson!({ "credits": balance as i64 - body.amount })),
+                            Some(bson!({ "credits": balance as i64, "reason": reason })),
+                        ),
+                )
+                .await;
+
+                HttpResponse::Ok().json(json!({ "_id": _id.to_string(), "credits": balance }))
+            }
+            Ok(None) => HttpResponse::Conflict().body("Insufficient credits"),
+            Err(er) if is_not_found(&er) => HttpResponse::NotFound().body("Player not found"),
+            Err(er) => HttpResponse::InternalServerError().body(er.to_string()),
+        }
+    })
+    .await
+}
+
+#[post("/transfer")]
+async fn transfer_credits(
+    body: web::Json<data_source::TransferCredits>,
+    req: HttpRequest,
+) -> impl Responder {
+    // The sender is authorized by their session, or by their pin when using a shared tablet
+    let sender = match &body.pin {
+        None => match is_user_authenticated_player(&req).await {
+            Ok(p) => p,
+            Err(e) => return e,
+        },
+        Some(pin) => {
+            let sender_id = match body.sender_id.as_deref().map(ObjectId::parse_str) {
+                Some(Ok(id)) => id,
+                Some(Err(_)) => return HttpResponse::BadRequest().body("Invalid sender ID"),
+                None => return HttpResponse::BadRequest().body("A pin needs the sender ID"),
+            };
+
+            let ip_key = AttemptKey::Ip(client_ip(&req));
+            let player_key = AttemptKey::Player(sender_id);
+            if let Err(e) = check_lockout(&[&ip_key, &player_key]).await {
+                return e;
+            }
+
+            match User::get(sender_id, ACTIVE_USERS).await {
+                Ok(Some(User::Player(p))) if p.pin == *pin => p,
+                Ok(_) => {
+                    record_failed_attempt(&[&ip_key, &player_key]).await;
+                    return HttpResponse::Unauthorized().body("Invalid pin");
+                }
+                Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+            }
+        }
+    };
+
+    idempotent(&req, sender._id, async move {
+        if let Err(e) = check_gameday(sender.gameday_id, GamedayStatus::allows_play).await {
+            return e;
+        }
+
+        let amount = match i64::try_from(body.amount) {
+            Ok(a) if a > 0 => a,
+            _ => return HttpResponse::BadRequest().body("Invalid amount"),
+        };
+
+        let recipient = match ObjectId::parse_str(&body.recipient) {
+            Ok(id) => match User::get(id, ACTIVE_USERS).await {
+                Ok(Some(User::Player(p))) => p,
+                Ok(_) => return HttpResponse::NotFound().body("Recipient not found"),
+                Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+            },
+            Err(_) => match User::find_players(
+                None,
+                Some(&body.recipient),
+                sender.gameday_id,
+                ACTIVE_USERS,
+            )
+            .await
+            {
+                Ok(players) => {
+                    let mut players: Vec<Player> = players
+                        .into_iter()
+                        .filter(|p| p.gameday_id == sender.gameday_id)
+                        .collect();
+
+                    match players.len() {
+                        0 => return HttpResponse::NotFound().body("Recipient not found"),
+                        1 => players.remove(0),
+                        _ => {
+                            return HttpResponse::Conflict()
+                                .body("Nickname is not unique, use the id of the recipient")
+                        }
+                    }
+                }
+                Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+            },
+        };
+
+        if recipient._id == sender._id {
+            return HttpResponse::BadRequest().body("Can not transfer credits to yourself");
+        }
+        if recipient.gameday_id != sender.gameday_id {
+            return HttpResponse::BadRequest().body("Recipient plays on another gameday");
+        }
+
+        let cap = match sender.gameday_id {
+            Some(gameday_id) => match Gameday::get(&gameday_id, GAMEDAYS).await {
+                Ok(gameday) => gameday.and_then(|g| g.transfer_cap),
+                Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+            },
+            None => None,
+        };
+
+        let res = User::transfer(sender._id, recipient._id, amount, cap, ACTIVE_USERS).await;
+
+        match res {
+            Ok(TransferResult::Done {
+                sender: balance, ..
+            }) => HttpResponse::Ok().json(json!({
+                "_id": sender._id.to_string(),
+                "recipient_id": recipient._id.to_string(),
+                "credits": balance,
+            })),
+            Ok(TransferResult::InsufficientCredits) => {
+                HttpResponse::PaymentRequired().body("Insufficient credits")
+            }
+            Ok(TransferResult::CapExceeded(remaining)) => HttpResponse::Conflict().body(format!(
+                "Transfer cap exceeded, {} credits can still be transferred",
+                remaining
+            )),
+            Err(er) if is_not_found(&er) => HttpResponse::NotFound().body("Recipient not found"),
+            Err(er) if is_transactions_unsupported(&er) => transactions_required(er),
+            Err(er) => HttpResponse::InternalServerError().body(er.to_string()),
+        }
+    })
+    .await
+}
+
+#[get("/user/{id}")]
+async fn get_user(path: web::Path<String>, query: web::Query<GamedayFilter>) -> impl Responder {
+    let id = path.into_inner();
+
+    let gameday_id = match query.parse() {
+        Ok(g) => g,
+        Err(e) => return e,
+    };
+
+    match id.as_str() {
+        "player" => get_user_by_role(Roles::Player, gameday_id).await,
+        "dealer" => get_user_by_role(Roles::Dealer, None).await,
+        "cashier" => get_user_by_role(Roles::Cashier, None).await,
+        "admin" => get_user_by_role(Roles::Admin, None).await,
+        id => match ObjectId::parse_str(id) {
+            Ok(id) => get_user_by_id(id).await,
+            Err(e) => HttpResponse::NotFound().body(e.to_string()),
+        },
+    }
+}
+
+async fn get_user_by_role(role: Roles, gameday_id: Option<ObjectId>) -> HttpResponse {
+    let users = User::get_by_role(role, gameday_id, ACTIVE_USERS).await;
+
+    let users = match users {
+        Ok(u) => u,
+        Err(e) => {
+            return HttpResponse::InternalServerError().body(e.to_string());
+        }
+    };
+
+    let res = users.into_iter().map(DBUser::into).collect::<Vec<User>>();
+
+    let res = res
+        .into_iter()
+        .map(|u: User| u.get_json_value())
+        .collect::<Vec<serde_json::Value>>();
+
+    HttpResponse::Ok().json(res)
+}
+async fn get_user_by_id(_id: ObjectId) -> HttpResponse {
+    let user = User::get(_id, ACTIVE_USERS).await;
+
+    match user {
+        Ok(Some(user)) => {
+            let usr = user.get_json_value();
+
+            HttpResponse::Ok().json(usr)
+        }
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+        _ => HttpResponse::NotFound().body("User not found"),
+    }
+}
+
+#[derive(Deserialize)]
+struct QrQuery {
+    format: Option<QrFormat>,
+}
+
+/// QR code of a player id, shown by the guest and scanned by a dealer tablet to seat them.
+#[get("/user/{user_id}/qr")]
+async fn get_user_qr(
+    path: web::Path<String>,
+    query: web::Query<QrQuery>,
+    req: HttpRequest,
+) -> impl Responder {
+    let _id = match ObjectId::parse_str(path.as_str()) {
+        Ok(id) => id,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid ID"),
+    };
+
+    if let Err(e) = is_self_or_staff(&req, _id).await {
+        return e;
+    }
+
+    match User::get(_id, ACTIVE_USERS).await {
+        Ok(Some(User::Player(_))) => {}
+        Ok(Some(User::Dealer(_))) => {
+            return HttpResponse::BadRequest().body("User Id does not refer to a player");
+        }
+        Ok(None) => return HttpResponse::NotFound().body("User not found"),
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    }
+
+    let format = query.format.unwrap_or(QrFormat::Svg);
+
+    match qr::render(&_id.to_string(), format) {
+        Ok(bytes) => HttpResponse::Ok()
+            .content_type(format.content_type())
+            .body(bytes),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+/// Lets a player access their own data, and staff the data of every player.
+async fn is_self_or_staff(req: &HttpRequest, user_id: ObjectId) -> Result<(), HttpResponse> {
+    match get_session_user(req).await {
+        Ok(User::Player(p)) if p._id == user_id => Ok(()),
+        Ok(User::Dealer(d)) if !d.disabled => Ok(()),
+        Ok(_) => Err(HttpResponse::Forbidden().body("Not allowed to access this user")),
+        Err(e) => Err(e),
+    }
+}
+
+#[get("/user/{id}/transactions")]
+async fn get_user_transactions(path: web::Path<String>, req: HttpRequest) -> impl Responder {
+    let _id = match ObjectId::parse_str(path.as_str()) {
+        Ok(id) => id,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid ID"),
+    };
+
+    if let Err(e) = is_self_or_staff(&req, _id).await {
+        return e;
+    }
+
+    match Transaction::get_by_user(_id, TRANSACTIONS).await {
+        Ok(transactions) => {
+            let json: Vec<serde_json::Value> =
+                transactions.iter().map(|t| t.get_json_value()).collect();
+            HttpResponse::Ok().json(json)
+        }
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[post("/dealer/register")]
+async fn register_dealer(
+    body: web::Json<data_source::RegisterDealer>,
+    req: HttpRequest,
+) -> impl Responder {
+    let dealer = match is_user_authorized(&req, Permission::ManageDealers).await {
+        Ok(d) => d,
+        Err(e) => return e,
+    };
+
+    let role = body.role.unwrap_or(Roles::Dealer);
+    if !role.is_staff() {
+        return HttpResponse::BadRequest().body("Role has to be Dealer, Cashier or Admin");
+    }
+
+    let pw = match hash_password(body.password.as_str()) {
+        Ok(pass) => pass,
+        Err(e) => {
+            return HttpResponse::BadRequest().body(e);
+        }
+    };
+
+    let d = User::Dealer(Dealer {
+        name: body.name.to_string(),
+        _id: ObjectId::new(),
+        password: pw,
+        role,
+        disabled: false,
+    });
+
+    let r = User::new(d, ACTIVE_USERS).await;
+
+    match r {
+        Ok(User::Dealer(d)) => {
+            audit(
+                AuditEntry::new(
+                    &dealer,
+                    AuditAction::RegisterDealer,
+                    Some(d._id.to_string()),
+                )
+                .with_change(
+                    None,
+                    Some(bson!({ "name": d.name.as_str(), "role": d.role.to_string() })),
+                ),
+            )
+            .await;
+
+            HttpResponse::Ok().body("success".to_string())
+        }
+        Ok(User::Player(_)) => HttpResponse::InternalServerError()
+            .body("Recieved User::Player where only User::Dealer was expected"),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[post("/dealer/login")]
+async fn login_dealer(
+    body: web::Json<data_source::LoginDealer>,
+    req: HttpRequest,
+) -> impl Responder {
+    let password = body.password.as_str();
+    let name = body.name.as_str();
+
+    let ip_key = AttemptKey::Ip(client_ip(&req));
+    let dealer_key = AttemptKey::Dealer(name.to_string());
+
+    if let Err(e) = check_lockout(&[&ip_key, &dealer_key]).await {
+        return e;
+    }
+
+    let usr = User::get_by_name(name, ACTIVE_USERS).await;
+
+    let usr = match usr {
+        Ok(u) => u,
+        Err(e) => {
+            return HttpResponse::InternalServerError().body(e.to_string());
+        }
+    };
+
+    let usr = match usr {
+        None => {
+            record_failed_attempt(&[&ip_key, &dealer_key]).await;
+            return HttpResponse::NotFound().body("User not found");
+        }
+        Some(u) => u,
+    };
+
+    match usr {
+        User::Player(_) => HttpResponse::Unauthorized().body("User is not a Dealer".to_string()),
+        User::Dealer(u) => {
+            let is_aut = u.is_authenticated(password).await;
+
+            match is_aut {
+                Ok(true) => {}
+                Ok(false) => {
+                    record_failed_attempt(&[&ip_key, &dealer_key]).await;
+                    return HttpResponse::Unauthorized().body("Wrong Password".to_string());
+                }
+                Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+            }
+
+            if let Err(e) = LoginAttempt::clear(&dealer_key, LOGIN_ATTEMPTS).await {
+                return HttpResponse::InternalServerError().body(e.to_string());
+            }
+
+            if u.disabled {
+                return HttpResponse::Forbidden().body("Dealer account is disabled");
+            }
+
+            let session = Session::new(u._id, dealer_session_hours(), SESSIONS).await;
+
+            match session {
+                Ok((session, token)) => HttpResponse::Ok().json(json!({
+                  "_id": u._id.to_string(),
+                  "token": token,
+                  "expires_at": session.expires_at.try_to_rfc3339_string().unwrap_or_default(),
+                })),
+                Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+            }
+        }
+    }
+}
+
+#[get("/dealer")]
+async fn get_dealers(req: HttpRequest) -> impl Responder {
+    let auth = is_user_authorized(&req, Permission::ManageDealers).await;
+    match auth {
+        Ok(_) => {}
+        Err(e) => return e,
+    }
+
+    let mut staff = vec![];
+    for role in [Roles::Dealer, Roles::Cashier, Roles::Admin] {
+        match User::get_by_role(role, None, ACTIVE_USERS).await {
+            Ok(users) => staff.extend(users),
+            Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+        }
+    }
+
+    let res = staff
+        .into_iter()
+        .map(|u| User::from(u).get_json_value())
+        .collect::<Vec<serde_json::Value>>();
+
+    HttpResponse::Ok().json(res)
+}
+
+#[patch("/dealer/password")]
+async fn change_own_password(
+    body: web::Json<data_source::ChangePassword>,
+    req: HttpRequest,
+) -> impl Responder {
+    let mut dealer = match is_user_authenticated_dealer(&req).await {
+        Ok(d) => d,
+        Err(e) => return e,
+    };
+
+    match dealer.is_authenticated(body.old_password.as_str()).await {
+        Ok(true) => {}
+        Ok(false) => return HttpResponse::Unauthorized().body("Wrong Password"),
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    }
+
+    dealer.password = match hash_password(body.new_password.as_str()) {
+        Ok(pw) => pw,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+
+    let entry = AuditEntry::new(
+        &dealer,
+        AuditAction::ChangePassword,
+        Some(dealer._id.to_string()),
+    );
+    let dealer_id = dealer._id;
+    let res = User::patch(dealer._id, User::Dealer(dealer).into(), ACTIVE_USERS).await;
+
+    match res {
+        Ok(Some(_)) => {
+            audit(entry).await;
+
+            // A stolen token must not outlive the old password, only this session stays valid
+            let token = match get_bearer_token(&req) {
+                Ok(t) => t,
+                Err(e) => return e,
+            };
+            match Session::revoke_others(dealer_id, token, SESSIONS).await {
+                Ok(_) => HttpResponse::Ok().body("success".to_string()),
+                Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+            }
+        }
+        Ok(None) => HttpResponse::NotFound().body("Dealer not found"),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[patch("/dealer/{dealer_id}/password")]
+async fn reset_dealer_password(
+    path: web::Path<String>,
+    body: web::Json<data_source::ResetPassword>,
+    req: HttpRequest,
+) -> impl Responder {
+    let admin = match is_user_authorized(&req, Permission::ManageDealers).await {
+        Ok(a) => a,
+        Err(e) => return e,
+    };
+
+    let mut dealer = match get_dealer(path.as_str()).await {
+        Ok(d) => d,
+        Err(e) => return e,
+    };
+
+    dealer.password = match hash_password(body.new_password.as_str()) {
+        Ok(pw) => pw,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+
+    let _id = dealer._id;
+    let res = User::patch(_id, User::Dealer(dealer).into(), ACTIVE_USERS).await;
+
+    match res {
+        Ok(Some(_)) => {}
+        Ok(None) => return HttpResponse::NotFound().body("Dealer not found"),
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    }
+
+    audit(AuditEntry::new(
+        &admin,
+        AuditAction::ResetPassword,
+        Some(_id.to_string()),
+    ))
+    .await;
+
+    match Session::revoke_all(_id, SESSIONS).await {
+        Ok(_) => HttpResponse::Ok().body("success".to_string()),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[patch("/dealer/{dealer_id}/status")]
+async fn set_dealer_status(
+    path: web::Path<String>,
+    body: web::Json<data_source::DealerStatus>,
+    req: HttpRequest,
+) -> impl Responder {
+    let admin = match is_user_authorized(&req, Permission::ManageDealers).await {
+        Ok(a) => a,
+        Err(e) => return e,
+    };
+
+    let mut dealer = match get_dealer(path.as_str()).await {
+        Ok(d) => d,
+        Err(e) => return e,
+    };
+
+    if dealer._id == admin._id {
+        return HttpResponse::BadRequest()
+            .body("You can not change the status of your own account");
+    }
+
+    let before = dealer.disabled;
+    dealer.disabled = body.disabled;
+
+    let _id = dealer._id;
+    let res = User::patch(_id, User::Dealer(dealer).into(), ACTIVE_USERS).await;
+
+    match res {
+        Ok(Some(_)) => {}
+        Ok(None) => return HttpResponse::NotFound().body("Dealer not found"),
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    }
+
+    audit(
+        AuditEntry::new(&admin, AuditAction::SetDealerStatus, Some(_id.to_string())).with_change(
+            Some(bson!({ "disabled": before })),
+            Some(bson!({ "disabled": body.disabled })),
+        ),
+    )
+    .await;
+
+    if body.disabled {
+        if let Err(e) = Session::revoke_all(_id, SESSIONS).await {
+            return HttpResponse::InternalServerError().body(e.to_string());
+        }
+    }
+
+    HttpResponse::Ok().body("success".to_string())
+}
+
+#[delete("/dealer/{dealer_id}")]
+async fn delete_dealer(path: web::Path<String>, req: HttpRequest) -> impl Responder {
+    let admin = match is_user_authorized(&req, Permission::ManageDealers).await {
+        Ok(a) => a,
+        Err(e) => return e,
+    };
+
+    let dealer = match get_dealer(path.as_str()).await {
+        Ok(d) => d,
+        Err(e) => return e,
+    };
+
+    if dealer._id == admin._id {
+        return HttpResponse::BadRequest().body("You can not delete your own account");
+    }
+
+    let res = User::delete_staff(dealer._id, ACTIVE_USERS).await;
+
+    match res {
+        Ok(true) => {}
+        Ok(false) => return HttpResponse::NotFound().body("Dealer not found"),
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    }
+
+    audit(
+        AuditEntry::new(
+            &admin,
+            AuditAction::DeleteDealer,
+            Some(dealer._id.to_string()),
+        )
+        .with_change(
+            Some(bson!({ "name": dealer.name.as_str(), "role": dealer.role.to_string() })),
+            None,
+        ),
+    )
+    .await;
+
+    match Session::revoke_all(dealer._id, SESSIONS).await {
+        Ok(_) => HttpResponse::Ok().body("success".to_string()),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+async fn get_dealer(id: &str) -> Result<Dealer, HttpResponse> {
+    let _id = match ObjectId::parse_str(id) {
+        Ok(id) => id,
+        Err(_) => return Err(HttpResponse::BadRequest().body("Invalid ID")),
+    };
+
+    match User::get(_id, ACTIVE_USERS).await {
+        Ok(Some(User::Dealer(d))) => Ok(d),
+        Ok(Some(User::Player(_))) => {
+            Err(HttpResponse::BadRequest().body("User Id does not refer to a dealer"))
+        }
+        Ok(None) => Err(HttpResponse::NotFound().body("Dealer not found")),
+        Err(e) => Err(HttpResponse::InternalServerError().body(e.to_string())),
+    }
+}
+
+#[post("/dealer/logout")]
+async fn logout_dealer(req: HttpRequest) -> impl Responder {
+    let token = match get_bearer_token(&req) {
+        Ok(t) => t,
+        Err(e) => return e,
+    };
+
+    let res = Session::revoke(token, SESSIONS).await;
+
+    match res {
+        Ok(true) => HttpResponse::Ok().body("success".to_string()),
+        Ok(false) => HttpResponse::NotFound().body("Session not found"),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[delete("/dealer/{dealer_id}/sessions")]
+async fn revoke_dealer_sessions(path: web::Path<String>, req: HttpRequest) -> impl Responder {
+    let dealer = match is_user_authenticated_dealer(&req).await {
+        Ok(d) => d,
+        Err(e) => return e,
+    };
+
+    let id = ObjectId::parse_str(path.as_str());
+    let _id = match id {
+        Ok(id) => id,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid ID"),
+    };
+
+    if dealer._id != _id && !dealer.role.has_permission(Permission::ManageDealers) {
+        return HttpResponse::Forbidden().body("Only admins can revoke sessions of other dealers");
+    }
+
+    let res = Session::revoke_all(_id, SESSIONS).await;
+
+    match res {
+        Ok(count) => {
+            audit(AuditEntry::new(
+                &dealer,
+                AuditAction::RevokeSessions,
+                Some(_id.to_string()),
+            ))
+            .await;
+
+            HttpResponse::Ok().json(json!({ "revoked": count }))
+        }
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[get("/")]
+async fn index() -> impl Responder {
+    HttpResponse::PermanentRedirect()
+        .insert_header(("Location", "https://www.youtube.com/watch?v=R_ijlnDtKa4"))
+        .finish()
+}
+
+/// Creates `amount` pending players for the gameday and returns their pins.
+async fn create_pin_batch(gameday: &Gameday, amount: u64) -> io::Result<Vec<String>> {
+    let pin_format = PinFormat::from_env();
+    let mut pins = vec![];
+
+    for _ in 0..amount {
+        let data = User::Player(Player::new_pending(
+            gameday.initial_player_credits,
+            Some(gameday._id),
+            &pin_format,
+        ));
+
+        match User::new(data, PENDING_USERS).await {
+            Ok(User::Player(p)) => pins.push(p.pin),
+            Ok(User::Dealer(_)) => {}
+            Err(e) => return Err(io::Error::other(e.to_string())),
+        }
+    }
+
+    Ok(pins)
+}
+
+/// Usage: `-p <gameday_id> <amount> [--link]` - writes a printable card sheet to pins.html
+async fn generate_pins(args: Vec<String>) -> io::Result<()> {
+    let location = args.iter().position(|x| x == "-p").unwrap();
+    let gameday_id = ObjectId::parse_str(args.get(location + 1).expect("No gameday id given"))
+        .expect("Invalid gameday id");
+    let amount: u64 = args
+        .get(location + 2)
+        .expect("No amount given")
+        .parse()
+        .expect("Amount is not a number");
+    let link = args.contains(&"--link".to_string());
+
+    if link {
+        qr::registration_base()?;
+    }
+
+    let gameday = Gameday::get(&gameday_id, GAMEDAYS)
+        .await
+        .map_err(|e| io::Error::other(e.to_string()))?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Gameday not found"))?;
+
+    if !gameday.status.allows_new_pins() {
+        return Err(io::Error::other(format!(
+            "No pins can be created while the gameday is {}",
+            gameday.status
+        )));
+    }
+
+    let pins = create_pin_batch(&gameday, amount).await?;
+
+    for pin in &pins {
+        println!("{}", pin);
+    }
+
+    let sheet = pin_sheet::render(&gameday, &pins, link)?;
+
+    let mut file = File::create("pins.html")?;
+    file.write_all(sheet.as_bytes())?;
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct PinSheetBody {
+    amount: u64,
+    #[serde(default)]
+    link: bool,
+}
+
+const MAX_PIN_SHEET_AMOUNT: u64 = 500;
+
+#[post("/gameday/{gameday_id}/pin_sheet")]
+async fn create_pin_sheet(
+    path: web::Path<String>,
+    body: web::Json<PinSheetBody>,
+    req: HttpRequest,
+) -> impl Responder {
+    let dealer = match is_user_authorized(&req, Permission::CreatePins).await {
+        Ok(d) => d,
+        Err(e) => return e,
+    };
+
+    if body.amount == 0 || body.amount > MAX_PIN_SHEET_AMOUNT {
+        return HttpResponse::BadRequest().body(format!(
+            "Amount has to be between 1 and {}",
+            MAX_PIN_SHEET_AMOUNT
+        ));
+    }
+
+    if body.link {
+        if let Err(e) = qr::registration_base() {
+            return HttpResponse::InternalServerError().body(e.to_string());
+        }
+    }
+
+    let gameday_id = match ObjectId::parse_str(path.as_str()) {
+        Ok(id) => id,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid Gameday ID"),
+    };
+
+    let gameday = match Gameday::get(&gameday_id, GAMEDAYS).await {
+        Ok(Some(g)) => g,
+        Ok(None) => return HttpResponse::NotFound().body("Gameday not found"),
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+
+    if !gameday.status.allows_new_pins() {
+        return gameday_not_allowed(gameday.status);
+    }
+
+    let pins = match create_pin_batch(&gameday, body.amount).await {
+        Ok(p) => p,
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+
+    audit(
+        AuditEntry::new(
+            &dealer,
+            AuditAction::CreatePins,
+            Some(gameday_id.to_string()),
+        )
+        .with_change(None, Some(bson!({ "amount": pins.len() as i64 }))),
+    )
+    .await;
+
+    match pin_sheet::render(&gameday, &pins, body.link) {
+        Ok(sheet) => HttpResponse::Ok()
+            .content_type("text/html; charset=utf-8")
+            .body(sheet),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[post("/user/recover")]
+async fn recover_pin(body: web::Json<data_source::RecoverPin>, req: HttpRequest) -> impl Responder {
+    let dealer = match is_user_authorized(&req, Permission::RecoverPins).await {
+        Ok(d) => d,
+        Err(e) => return e,
+    };
+
+    let gameday_id = match ObjectId::parse_str(&body.gameday_id) {
+        Ok(id) => id,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid Gameday ID"),
+    };
+
+    if let Err(e) = check_gameday(Some(gameday_id), GamedayStatus::allows_new_pins).await {
+        return e;
+    }
+
+    let user_id = match &body.user_id {
+        Some(id) => match ObjectId::parse_str(id) {
+            Ok(id) => match User::get(id, ACTIVE_USERS).await {
+                Ok(Some(User::Player(p))) if p.gameday_id == Some(gameday_id) => id,
+                Ok(_) => return HttpResponse::NotFound().body("User not found"),
+                Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+            },
+            Err(_) => return HttpResponse::BadRequest().body("Invalid User ID"),
+        },
+        None => {
+            if body.name.is_none() && body.nickname.is_none() {
+                return HttpResponse::BadRequest().body("Provide user_id, name or nickname");
+            }
+
+            let players = User::find_players(
+                body.name.as_deref(),
+                body.nickname.as_deref(),
+                Some(gameday_id),
+                ACTIVE_USERS,
+            )
+            .await;
+            let players = match players {
+                Ok(p) => p,
+                Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+            };
+
+            match players.as_slice() {
+                [] => return HttpResponse::NotFound().body("User not found"),
+                [player] => player._id,
+                _ => {
+                    // let the dealer pick the right guest and retry with the user_id
+                    return HttpResponse::Conflict().json(
+                        players
+                            .into_iter()
+                            .map(|p| User::Player(p).get_json_value())
+                            .collect::<Vec<serde_json::Value>>(),
+                    );
+                }
+            }
+        }
+    };
+
+    let pin = match User::reissue_pin(user_id, ACTIVE_USERS).await {
+        Ok(Some(pin)) => pin,
+        Ok(None) => return HttpResponse::NotFound().body("User not found"),
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+
+    if let Err(e) = Session::revoke_all(user_id, SESSIONS).await {
+        return HttpResponse::InternalServerError().body(e.to_string());
+    }
+
+    if let Err(e) = LoginAttempt::clear(&AttemptKey::Player(user_id), LOGIN_ATTEMPTS).await {
+        warn!("Failed to clear lockout of {}: {}", user_id, e);
+    }
+
+    audit(AuditEntry::new(
+        &dealer,
+        AuditAction::ReissuePin,
+        Some(user_id.to_string()),
+    ))
+    .await;
+
+    HttpResponse::Ok().json(json!({
+        "_id": user_id.to_string(),
+        "pin": pin,
+    }))
+}
+
+#[derive(Deserialize)]
+struct AuditLogQuery {
+    actor: Option<String>,
+    target: Option<String>,
+    action: Option<AuditAction>,
+    from: Option<String>,
+    to: Option<String>,
+    limit: Option<i64>,
+}
+
+const DEFAULT_AUDIT_LOG_LIMIT: i64 = 100;
+const MAX_AUDIT_LOG_LIMIT: i64 = 1000;
+
+#[get("/audit_log")]
+async fn get_audit_log(query: web::Query<AuditLogQuery>, req: HttpRequest) -> impl Responder {
+    let auth = is_user_authorized(&req, Permission::ViewAuditLog).await;
+    match auth {
+        Ok(_) => {}
+        Err(e) => return e,
+    }
+
+    let query = query.into_inner();
+
+    let actor_id = match query.actor.as_deref().map(ObjectId::parse_str) {
+        None => None,
+        Some(Ok(id)) => Some(id),
+        Some(Err(_)) => return HttpResponse::BadRequest().body("Invalid actor ID"),
+    };
+
+    let from = match query.from.as_deref().map(DateTime::parse_rfc3339_str) {
+        None => None,
+        Some(Ok(d)) => Some(d),
+        Some(Err(_)) => return HttpResponse::BadRequest().body("from is not a RFC 3339 date"),
+    };
+    let to = match query.to.as_deref().map(DateTime::parse_rfc3339_str) {
+        None => None,
+        Some(Ok(d)) => Some(d),
+        Some(Err(_)) => return HttpResponse::BadRequest().body("to is not a RFC 3339 date"),
+    };
+
+    let filter = AuditFilter {
+        actor_id,
+        target: query.target,
+        action: query.action,
+        from,
+        to,
+        limit: query
+            .limit
+            .unwrap_or(DEFAULT_AUDIT_LOG_LIMIT)
+            .clamp(1, MAX_AUDIT_LOG_LIMIT),
+    };
+
+    let res = AuditEntry::find(filter, AUDIT_LOG).await;
+
+    match res {
+        Ok(entries) => HttpResponse::Ok().json(
+            entries
+                .into_iter()
+                .map(|e| {
+                    json!({
+                        "_id": e._id.to_string(),
+                        "actor_id": e.actor_id.to_string(),
+                        "actor_role": e.actor_role.to_string().to_lowercase(),
+                        "action": e.action.to_string(),
+                        "target": e.target,
+                        "before": e.before.map(Bson::into_relaxed_extjson),
+                        "after": e.after.map(Bson::into_relaxed_extjson),
+                        "created_at": e.created_at.try_to_rfc3339_string().unwrap_or_default(),
+                    })
+                })
+                .collect::<Vec<serde_json::Value>>(),
+        ),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+/// Audit entries are written after the action succeeded, a failed write must not undo it.
+async fn audit(entry: AuditEntry) {
+    if let Err(e) = entry.insert(AUDIT_LOG).await {
+        warn!("Failed to write audit entry {:?}: {}", entry, e);
+    }
+}
+
+#[get("/lockout")]
+async fn get_lockouts(req: HttpRequest) -> impl Responder {
+    let auth = is_user_authorized(&req, Permission::ClearLockouts).await;
+    match auth {
+        Ok(_) => {}
+        Err(e) => return e,
+    }
+
+    let res = LoginAttempt::get_locked(LOGIN_ATTEMPTS).await;
+
+    match res {
+        Ok(attempts) => HttpResponse::Ok().json(
+            attempts
+                .into_iter()
+                .map(|a| {
+                    json!({
+                        "key": a._id,
+                        "failures": a.failures,
+                        "locked_until": a.locked_until
+                            .and_then(|l| l.try_to_rfc3339_string().ok())
+                            .unwrap_or_default(),
+                    })
+                })
+                .collect::<Vec<serde_json::Value>>(),
+        ),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[post("/lockout/clear")]
+async fn clear_lockout(
+    body: web::Json<data_source::ClearLockout>,
+    req: HttpRequest,
+) -> impl Responder {
+    let admin = match is_user_authorized(&req, Permission::ClearLockouts).await {
+        Ok(a) => a,
+        Err(e) => return e,
+    };
+
+    let mut keys = vec![];
+    if let Some(player_id) = &body.player_id {
+        match ObjectId::parse_str(player_id) {
+            Ok(id) => keys.push(AttemptKey::Player(id)),
+            Err(_) => return HttpResponse::BadRequest().body("Invalid player ID"),
+        }
+    }
+    if let Some(pin) = &body.pin {
+        keys.push(AttemptKey::Pin(pin.to_string()));
+    }
+    if let Some(ip) = &body.ip {
+        keys.push(AttemptKey::Ip(ip.clone()));
+    }
+    if let Some(dealer) = &body.dealer_name {
+        keys.push(AttemptKey::Dealer(dealer.clone()));
     }
-}
 
-async fn get_user_by_role(role: data_source::Roles) -> HttpResponse {
-    let users = User::get_by_role(role, ACTIVE_USERS).await;
+    if keys.is_empty() {
+        return HttpResponse::BadRequest()
+            .body("Nothing to clear - provide player_id, pin, ip or dealer_name");
+    }
 
-    let users = match users {
-        Ok(u) => u,
-        Err(e) => {
-            return HttpResponse::InternalServerError().body(e.to_string());
+    let mut cleared = vec![];
+    for key in keys {
+        match LoginAttempt::clear(&key, LOGIN_ATTEMPTS).await {
+            Ok(true) => cleared.push(key.to_string()),
+            Ok(false) => {}
+            Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
         }
-    };
+    }
 
-    let res = users.into_iter().map(DBUser::into).collect::<Vec<User>>();
+    info!("Lockouts {:?} cleared by {}", cleared, admin._id);
 
-    let res = res
-        .into_iter()
-        .map(|u: User| u.get_json_value())
-        .collect::<Vec<serde_json::Value>>();
+    audit(
+        AuditEntry::new(&admin, AuditAction::ClearLockout, None)
+            .with_change(None, Some(bson!({ "cleared": cleared.clone() }))),
+    )
+    .await;
 
-    HttpResponse::Ok().json(res)
+    HttpResponse::Ok().json(json!({ "cleared": cleared }))
 }
-async fn get_user_by_id(_id: ObjectId) -> HttpResponse {
-    let user = User::get(_id, ACTIVE_USERS).await;
-
-    match user {
-        Ok(Some(user)) => {
-            let usr = user.get_json_value();
 
-            HttpResponse::Ok().json(usr)
-        }
-        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
-        _ => HttpResponse::NotFound().body("User not found"),
-    }
-}
+/// Runs the handler at most once per Idempotency-Key header of the caller and replays the
+/// stored response for repeated requests. Requests without the header are run as usual.
+async fn idempotent(
+    req: &HttpRequest,
+    caller: ObjectId,
+    handler: impl Future<Output = HttpResponse>,
+) -> HttpResponse {
+    let key = match req.headers().get("Idempotency-Key") {
+        None => return handler.await,
+        Some(k) => match k.to_str() {
+            Ok(k) if !k.trim().is_empty() && k.len() <= 255 => k.trim(),
+            _ => return HttpResponse::BadRequest().body("Invalid Idempotency-Key"),
+        },
+    };
 
-#[post("/dealer/register")]
-async fn register_dealer(body: web::Json<data_source::RegisterDealer>) -> impl Responder {
-    let password = body.password.as_str();
+    let id = format!("{}:{} {}:{}", caller, req.method(), req.path(), key);
 
-    let salt: SaltString = SaltString::generate(&mut OsRng);
-    let argon2: Argon2 = Argon2::default();
+    match IdempotencyRecord::reserve(&id, IDEMPOTENCY_KEYS).await {
+        Ok(None) => {}
+        Ok(Some(record)) => return replay(record),
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    }
 
-    let password_hash = argon2.hash_password(password.as_bytes(), &salt);
+    let res = handler.await;
 
-    let pw = match password_hash {
-        Ok(pass) => pass,
-        Err(e) => {
-            return HttpResponse::BadRequest().body(e.to_string());
+    // The wrapped handlers write their credit changes atomically together with the ledger, so
+    // a server error means nothing was applied. Those are not stored, so the request can be
+    // retried with the same key.
+    if res.status().is_server_error() {
+        if let Err(e) = IdempotencyRecord::release(&id, IDEMPOTENCY_KEYS).await {
+            warn!("Failed to release idempotency key {}: {}", id, e);
         }
-    };
+        return res;
+    }
 
-    let d = User::Dealer(Dealer {
-        name: body.name.to_string(),
-        _id: ObjectId::new(),
-        password: pw.to_string(),
-    });
+    let status = res.status();
+    let content_type = res
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|c| c.to_str().ok())
+        .map(str::to_string);
 
-    let r = User::new(d, ACTIVE_USERS).await;
+    let (res, body) = res.into_parts();
+    let body = match actix_web::body::to_bytes(body).await {
+        Ok(b) => b,
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
 
-    match r {
-        Ok(_) => HttpResponse::Ok().body("success".to_string()),
-        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    let stored = String::from_utf8_lossy(&body).to_string();
+    if let Err(e) =
+        IdempotencyRecord::complete(&id, status.as_u16(), content_type, stored, IDEMPOTENCY_KEYS)
+            .await
+    {
+        warn!("Failed to store response for idempotency key {}: {}", id, e);
     }
-}
-
-#[post("/dealer/login")]
-async fn login_dealer(body: web::Json<data_source::LoginDealer>) -> impl Responder {
-    let password = body.password.as_str();
-    let name = body.name.as_str();
 
-    let usr = User::get_by_name(name, ACTIVE_USERS).await;
+    res.set_body(body).map_into_boxed_body()
+}
 
-    let usr = match usr {
-        Ok(u) => u,
-        Err(e) => {
-            return HttpResponse::InternalServerError().body(e.to_string());
+fn replay(record: IdempotencyRecord) -> HttpResponse {
+    let status = match record.status.and_then(|s| StatusCode::from_u16(s).ok()) {
+        Some(s) => s,
+        None => {
+            return HttpResponse::Conflict()
+                .body("A request with this Idempotency-Key is still in progress")
         }
     };
 
-    let usr = match usr {
-        None => return HttpResponse::NotFound().body("User not found"),
-        Some(u) => u,
-    };
+    let mut res = HttpResponse::build(status);
+    if let Some(content_type) = record.content_type {
+        res.content_type(content_type);
+    }
 
-    match usr {
-        User::Player(_) => HttpResponse::Unauthorized().body("User is not a Dealer".to_string()),
-        User::Dealer(u) => {
-            let is_aut = u.is_authenticated(password).await;
+    res.insert_header(("Idempotent-Replayed", "true"))
+        .body(record.body.unwrap_or_default())
+}
 
-            match is_aut {
-                Ok(o) => {
-                    if o {
-                        return HttpResponse::Ok().json(json!({
-                          "_id": u._id.to_string(),
-                        }));
-                    }
+/// Rejects the request if the gameday does not allow it in its current status.
+/// Users and games without a gameday are not restricted.
+async fn check_gameday(
+    gameday_id: Option<ObjectId>,
+    allows: fn(&GamedayStatus) -> bool,
+) -> Result<(), HttpResponse> {
+    let gameday_id = match gameday_id {
+        Some(g) => g,
+        None => return Ok(()),
+    };
 
-                    HttpResponse::Unauthorized().body("Wrong Password".to_string())
-                }
-                Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
-            }
-        }
+    match Gameday::get(&gameday_id, GAMEDAYS).await {
+        Ok(Some(g)) if !allows(&g.status) => Err(gameday_not_allowed(g.status)),
+        Ok(_) => Ok(()),
+        Err(e) => Err(HttpResponse::InternalServerError().body(e.to_string())),
     }
 }
 
-#[get("/")]
-async fn index() -> impl Responder {
-    HttpResponse::PermanentRedirect()
-        .insert_header(("Location", "https://www.youtube.com/watch?v=R_ijlnDtKa4"))
-        .finish()
+async fn check_player_gameday(
+    user_id: ObjectId,
+    allows: fn(&GamedayStatus) -> bool,
+) -> Result<(), HttpResponse> {
+    match User::get(user_id, ACTIVE_USERS).await {
+        Ok(Some(User::Player(p))) => check_gameday(p.gameday_id, allows).await,
+        Ok(_) => Ok(()),
+        Err(e) => Err(HttpResponse::InternalServerError().body(e.to_string())),
+    }
 }
 
-async fn generate_pins(args: Vec<String>) -> io::Result<()> {
-    let location = args.iter().position(|x| x == "-p").unwrap();
-    let amount: u64 = args.get(location + 1).unwrap().parse().unwrap();
+fn transactions_required(e: mongodb::error::Error) -> HttpResponse {
+    HttpResponse::ServiceUnavailable().body(e.to_string())
+}
 
-    let credits: u64 = args.get(location + 2).unwrap().parse().unwrap();
+fn gameday_not_allowed(status: GamedayStatus) -> HttpResponse {
+    HttpResponse::Conflict().body(format!("Not possible while the gameday is {}", status))
+}
 
-    let mut file = File::create("pins.txt")?;
+/// Proxies whose X-Forwarded-For header is trusted, configured as a comma separated list of
+/// addresses in TRUSTED_PROXIES.
+fn trusted_proxies() -> Vec<IpAddr> {
+    env::var("TRUSTED_PROXIES")
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|p| p.trim().parse().ok())
+        .collect()
+}
 
-    for _ in 0..amount {
-        let pin: u32 = rand::thread_rng().gen_range(100_000..999_999);
+/// Address the lockouts are counted for. Forwarded headers can be set by anyone, so they are
+/// only used if the request came through a trusted proxy.
+fn client_ip(req: &HttpRequest) -> String {
+    let peer = match req.peer_addr() {
+        Some(addr) => addr.ip(),
+        None => return "unknown".to_string(),
+    };
 
-        let data = User::Player(Player {
-            name: None,
-            nickname: None,
-            _id: ObjectId::new(),
-            credits: credits,
-            pin,
-            active_game: None,
-        });
+    let forwarded_for = req
+        .headers()
+        .get("X-Forwarded-For")
+        .and_then(|h| h.to_str().ok());
 
-        let u = User::new(data, PENDING_USERS).await;
+    forwarded_client(peer, forwarded_for, &trusted_proxies()).to_string()
+}
 
-        match u {
-            Ok(_) => {}
-            Err(e) => {
-                println!("{:?}", e);
-                break;
+/// Walks the X-Forwarded-For chain from the closest hop and returns the first address that is
+/// not a trusted proxy. Entries left of it were added by the client and are ignored.
+fn forwarded_client(peer: IpAddr, forwarded_for: Option<&str>, trusted: &[IpAddr]) -> IpAddr {
+    if !trusted.contains(&peer) {
+        return peer;
+    }
+
+    let hops = forwarded_for.unwrap_or_default().split(',').rev();
+    let mut client = peer;
+    for hop in hops {
+        match hop.trim().parse::<IpAddr>() {
+            Ok(ip) => {
+                client = ip;
+                if !trusted.contains(&ip) {
+                    break;
+                }
             }
+            Err(_) => break,
         }
+    }
 
-        println!("{}", pin);
+    client
+}
 
-        let mut p = pin.to_string();
-        p.push('\n');
+async fn check_lockout(keys: &[&AttemptKey]) -> Result<(), HttpResponse> {
+    let res = LoginAttempt::locked_until(keys, LOGIN_ATTEMPTS).await;
 
-        file.write_all(p.to_string().as_bytes())?;
+    match res {
+        Ok(None) => Ok(()),
+        Ok(Some(until)) => {
+            let keys: Vec<String> = keys.iter().map(|k| k.to_string()).collect();
+            warn!("Rejected attempt for locked {:?}", keys);
+            Err(too_many_attempts(until))
+        }
+        Err(e) => Err(HttpResponse::InternalServerError().body(e.to_string())),
     }
+}
 
-    Ok(())
+async fn record_failed_attempt(keys: &[&AttemptKey]) {
+    for key in keys {
+        match LoginAttempt::record_failure(key, LOGIN_ATTEMPTS).await {
+            Ok(Some(until)) => warn!(
+                "Locked {} until {}",
+                key,
+                until.try_to_rfc3339_string().unwrap_or_default()
+            ),
+            Ok(None) => {}
+            Err(e) => warn!("Failed to record failed attempt for {}: {}", key, e),
+        }
+    }
 }
 
-async fn create_default_dealer() -> io::Result<()> {
-    let password: u64 = OsRng::default().gen();
-    let name: u64 = OsRng::default().gen();
+fn too_many_attempts(until: DateTime) -> HttpResponse {
+    let retry_after =
+        ((until.timestamp_millis() - DateTime::now().timestamp_millis()) / 1000).max(1);
 
-    let password = password.to_string();
-    let name = name.to_string();
+    HttpResponse::TooManyRequests()
+        .insert_header((header::RETRY_AFTER, retry_after.to_string()))
+        .body(format!(
+            "Too many failed attempts - try again in {} seconds",
+            retry_after
+        ))
+}
 
+fn hash_password(password: &str) -> Result<String, String> {
     let salt: SaltString = SaltString::generate(&mut OsRng);
     let argon2: Argon2 = Argon2::default();
 
-    let password_hash = argon2.hash_password(password.as_bytes(), &salt);
+    argon2
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| e.to_string())
+}
+
+async fn create_default_dealer() -> io::Result<()> {
+    let password: u64 = OsRng.gen();
+    let name: u64 = OsRng.gen();
+
+    let password = password.to_string();
+    let name = name.to_string();
 
-    let pw = match password_hash {
+    let pw = match hash_password(password.as_str()) {
         Ok(pass) => pass,
         Err(e) => {
-            return Err(io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                e.to_string(),
-            ));
+            return Err(io::Error::new(std::io::ErrorKind::InvalidData, e));
         }
     };
 
-    println!("Default dealer created with name: {}", &name);
-    println!("Default dealer created with password: {}", &password);
+    println!("Default admin created with name: {}", &name);
+    println!("Default admin created with password: {}", &password);
 
     let d = User::Dealer(Dealer {
         name: name.to_string(),
         _id: ObjectId::new(),
-        password: pw.to_string(),
+        password: pw,
+        role: Roles::Admin,
+        disabled: false,
     });
 
     let r = User::new(d, ACTIVE_USERS).await;
 
     match r {
         Ok(_) => Ok(()),
-        Err(_) => {
-            return Err(io::Error::new(
-                std::io::ErrorKind::NotFound,
-                "Dealer creation failed",
-            ));
+        Err(_) => Err(io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "Dealer creation failed",
+        )),
+    }
+}
+
+/// Pins identify a player on registration, so they must never be handed out twice.
+/// Dealers have no pin and are excluded by the partial filter. The index is global rather than
+/// per gameday, because registration, pin recovery and transfers look players up by the pin alone.
+fn unique_pin_index() -> IndexModel {
+    IndexModel::builder()
+        .keys(doc! {"pin": 1})
+        .options(
+            IndexOptions::builder()
+                .name("pin_unique".to_string())
+                .unique(true)
+                .partial_filter_expression(doc! {"pin": {"$type": "string"}})
+                .build(),
+        )
+        .build()
+}
+
+/// Creates the unique pin index, replacing the plain one. Databases from before pins were unique
+/// may hold duplicates, those are logged and the plain index is kept until they are resolved.
+async fn create_unique_pin_index(coll: &Collection<User>) {
+    match coll.create_index(unique_pin_index()).await {
+        Ok(res) => {
+            info!("Created index: {:?}", res);
+            let _ = coll.drop_index("pin_1").await;
+        }
+        Err(e) => {
+            error!(
+                "Cannot create the unique pin index on {}: {}",
+                coll.name(),
+                e
+            );
+
+            let pipeline = vec![
+                doc! { "$match": { "pin": { "$type": "string" } } },
+                doc! { "$group": { "_id": "$pin", "users": { "$push": "$_id" }, "count": { "$sum": 1 } } },
+                doc! { "$match": { "count": { "$gt": 1 } } },
+            ];
+            let duplicates = match coll.aggregate(pipeline).await {
+                Ok(cursor) => cursor.try_collect::<Vec<_>>().await,
+                Err(e) => Err(e),
+            };
+
+            match duplicates {
+                Ok(duplicates) => {
+                    for duplicate in duplicates {
+                        error!(
+                            "Pin shared by {:?}, reissue it to all but one of them",
+                            duplicate.get_array("users").ok()
+                        );
+                    }
+                }
+                Err(e) => error!("Cannot look up duplicate pins: {}", e),
+            }
+        }
+    }
+}
+
+/// Freezes the standings of closed gamedays where freezing on close failed. Returns how many
+/// were frozen, gamedays that can not be frozen are logged and skipped.
+async fn freeze_missing_standings() -> Result<usize, mongodb::error::Error> {
+    let mut frozen = 0;
+
+    for gameday in Gameday::get_all(GAMEDAYS).await? {
+        if !matches!(
+            gameday.status,
+            GamedayStatus::Closed | GamedayStatus::Archived
+        ) || Standings::get(&gameday._id, STANDINGS).await?.is_some()
+        {
+            continue;
+        }
+
+        match Standings::freeze(&gameday, ACTIVE_USERS, STANDINGS).await {
+            Ok(_) => frozen += 1,
+            Err(e) => warn!(
+                "Could not freeze the standings of gameday {}: {}",
+                gameday._id, e
+            ),
         }
     }
+
+    Ok(frozen)
 }
 
 #[actix_web::main]
@@ -738,6 +2873,42 @@ async fn main() -> io::Result<()> {
 
     env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
 
+    if !data_source::detect_transaction_support(ACTIVE_USERS).await? {
+        warn!("The database does not support transactions, settlements, transfers and bets are unavailable until it runs as a replica set");
+    }
+
+    for source in [PENDING_USERS, ACTIVE_USERS] {
+        let collection = source.collection_identifier;
+        match User::migrate_numeric_pins(source).await {
+            Ok(0) => {}
+            Ok(n) => info!("Converted {} numeric pins in {}", n, collection),
+            Err(e) => error!("Could not convert numeric pins in {}: {}", collection, e),
+        }
+    }
+
+    match User::backfill_pending_expiry(PENDING_USERS).await {
+        Ok(0) => {}
+        Ok(n) => info!("Set the expiry of {} pending pins", n),
+        Err(e) => error!("Could not set the expiry of pending pins: {}", e),
+    }
+
+    let players = User::get_by_role(Roles::Player, None, ACTIVE_USERS).await;
+    let res = match players {
+        Ok(players) => Transaction::record_opening_balances(players, TRANSACTIONS).await,
+        Err(e) => Err(e),
+    };
+    match res {
+        Ok(0) => {}
+        Ok(n) => info!("Recorded the opening balance of {} players", n),
+        Err(e) => error!("Could not record opening balances: {}", e),
+    }
+
+    match freeze_missing_standings().await {
+        Ok(0) => {}
+        Ok(n) => info!("Froze the standings of {} closed gamedays", n),
+        Err(e) => error!("Could not freeze missing standings: {}", e),
+    }
+
     let client = ACTIVE_USERS.get_new_db_client().await?;
     let db = client.database(ACTIVE_USERS.database_identifier);
 
@@ -758,12 +2929,8 @@ async fn main() -> io::Result<()> {
         .expect("Cannot create index ACTIVE_USERS");
     info!("Created index: {:?}", res);
 
-    let usr_indices = IndexModel::builder().keys(doc! {"pin": 1}).build();
-    let res = coll
-        .create_index(usr_indices)
-        .await
-        .expect("Cannot create index ACTIVE_USERS");
-    info!("Created index: {:?}", res);
+    // replaces the plain pin index
+    create_unique_pin_index(&coll).await;
 
     let usr_indices = IndexModel::builder().keys(doc! {"role": 1}).build();
     let res = coll
@@ -787,7 +2954,18 @@ async fn main() -> io::Result<()> {
         .expect("Cannot create index PENDING_USERS");
     info!("Created index: {:?}", res);
 
-    let pen_usr_indices = IndexModel::builder().keys(doc! {"pin": 1}).build();
+    create_unique_pin_index(&coll).await;
+
+    let pen_usr_indices = IndexModel::builder()
+        .keys(doc! { "name": 1, "pin": 1})
+        .build();
+    let res = coll
+        .create_index(pen_usr_indices)
+        .await
+        .expect("Cannot create index PENDING_USERS");
+    info!("Created index: {:?}", res);
+
+    let pen_usr_indices = IndexModel::builder().keys(doc! {"gameday_id": 1}).build();
     let res = coll
         .create_index(pen_usr_indices)
         .await
@@ -795,7 +2973,12 @@ async fn main() -> io::Result<()> {
     info!("Created index: {:?}", res);
 
     let pen_usr_indices = IndexModel::builder()
-        .keys(doc! { "name": 1, "pin": 1})
+        .keys(doc! {"expires_at": 1})
+        .options(
+            IndexOptions::builder()
+                .expire_after(Duration::from_secs(0))
+                .build(),
+        )
         .build();
     let res = coll
         .create_index(pen_usr_indices)
@@ -811,11 +2994,108 @@ async fn main() -> io::Result<()> {
         .expect("Cannot create index GAMES");
     info!("Created index: {:?}", res);
 
+    let coll: Collection<Session> = db.collection(SESSIONS.collection_identifier);
+    let session_indices = IndexModel::builder()
+        .keys(doc! {"token_hash": 1})
+        .options(IndexOptions::builder().unique(true).build())
+        .build();
+    let res = coll
+        .create_index(session_indices)
+        .await
+        .expect("Cannot create index SESSIONS");
+    info!("Created index: {:?}", res);
+
+    let session_indices = IndexModel::builder().keys(doc! {"user_id": 1}).build();
+    let res = coll
+        .create_index(session_indices)
+        .await
+        .expect("Cannot create index SESSIONS");
+    info!("Created index: {:?}", res);
+
+    let session_indices = IndexModel::builder()
+        .keys(doc! {"expires_at": 1})
+        .options(
+            IndexOptions::builder()
+                .expire_after(Duration::from_secs(0))
+                .build(),
+        )
+        .build();
+    let res = coll
+        .create_index(session_indices)
+        .await
+        .expect("Cannot create index SESSIONS");
+    info!("Created index: {:?}", res);
+
+    let coll: Collection<AuditEntry> = db.collection(AUDIT_LOG.collection_identifier);
+    for keys in [
+        doc! {"actor_id": 1, "created_at": -1},
+        doc! {"target": 1, "created_at": -1},
+        doc! {"created_at": -1},
+    ] {
+        let res = coll
+            .create_index(IndexModel::builder().keys(keys).build())
+            .await
+            .expect("Cannot create index AUDIT_LOG");
+        info!("Created index: {:?}", res);
+    }
+
+    let coll: Collection<Transaction> = db.collection(TRANSACTIONS.collection_identifier);
+    let res = coll
+        .create_index(
+            IndexModel::builder()
+                .keys(doc! {"user_id": 1, "created_at": -1})
+                .build(),
+        )
+        .await
+        .expect("Cannot create index TRANSACTIONS");
+    info!("Created index: {:?}", res);
+
+    let coll: Collection<IdempotencyRecord> = db.collection(IDEMPOTENCY_KEYS.collection_identifier);
+    let idempotency_indices = IndexModel::builder()
+        .keys(doc! {"expires_at": 1})
+        .options(
+            IndexOptions::builder()
+                .expire_after(Duration::from_secs(0))
+                .build(),
+        )
+        .build();
+    let res = coll
+        .create_index(idempotency_indices)
+        .await
+        .expect("Cannot create index IDEMPOTENCY_KEYS");
+    info!("Created index: {:?}", res);
+
+    let coll: Collection<Bet> = db.collection(BETS.collection_identifier);
+    let res = coll
+        .create_index(
+            IndexModel::builder()
+                .keys(doc! {"game_id": 1, "status": 1, "created_at": 1})
+                .build(),
+        )
+        .await
+        .expect("Cannot create index BETS");
+    info!("Created index: {:?}", res);
+
+    let coll: Collection<LoginAttempt> = db.collection(LOGIN_ATTEMPTS.collection_identifier);
+    let attempt_indices = IndexModel::builder()
+        .keys(doc! {"last_failure": 1})
+        .options(
+            IndexOptions::builder()
+                .expire_after(Duration::from_secs(24 * 60 * 60))
+                .build(),
+        )
+        .build();
+    let res = coll
+        .create_index(attempt_indices)
+        .await
+        .expect("Cannot create index LOGIN_ATTEMPTS");
+    info!("Created index: {:?}", res);
+
     let coll: Collection<DBUser> = db.collection(ACTIVE_USERS.collection_identifier);
     let res = coll
-        .find_one(doc! {"role": "Dealer"})
+        .find_one(doc! {"role": Roles::Admin.to_string()})
         .await
-        .expect("Cannot find dealer ACTIVE_USERS");
+        .expect("Cannot find admin ACTIVE_USERS");
 
     if res.is_none() {
         let res = create_default_dealer().await;
@@ -830,7 +3110,7 @@ async fn main() -> io::Result<()> {
             }
         }
     } else {
-        println!("Dealer User exist - creating default dealer skipped");
+        println!("Admin User exist - creating default admin skipped");
     }
 
     HttpServer::new(|| {
@@ -839,92 +3119,176 @@ async fn main() -> io::Result<()> {
             .service(index)
             .service(create_gameday)
             .service(create_pending_user)
+            .service(get_pending_pins)
+            .service(revoke_all_pending_pins)
+            .service(revoke_pending_pin)
+            .service(create_pin_sheet)
             .service(register_user)
             .service(create_game)
             .service(get_game)
+            // before join_game, which would take "bets" for its action
+            .service(get_open_bets)
             .service(join_game)
             .service(get_user)
+            .service(get_user_qr)
             .service(get_all_games)
             .service(register_dealer)
             .service(login_dealer)
+            .service(logout_dealer)
+            .service(get_dealers)
+            .service(change_own_password)
+            .service(reset_dealer_password)
+            .service(set_dealer_status)
+            .service(delete_dealer)
+            .service(recover_pin)
+            .service(get_audit_log)
+            .service(get_lockouts)
+            .service(clear_lockout)
+            .service(revoke_dealer_sessions)
+            .service(logout_player)
+            .service(revoke_player_sessions)
+            .service(get_gamedays)
             .service(get_gameday)
+            .service(set_gameday_status)
+            .service(get_standings)
+            .service(create_gameday_template)
+            .service(get_gameday_templates)
+            .service(create_gameday_from_template)
             .service(patch_game)
             .service(delete_game)
+            .service(settle_game)
+            .service(place_bet)
+            .service(resolve_bets)
             .service(set_credits)
+            .service(adjust_credits)
+            .service(transfer_credits)
+            .service(get_user_transactions)
     })
     .bind(("0.0.0.0", 8080))?
     .run()
     .await
 }
 
-async fn is_user_authenticated_dealer(
-    dealer_id: Option<&HeaderValue>,
-    dealer_pw: Option<&HeaderValue>,
-) -> Result<(), HttpResponse> {
-    let dealer_id = match dealer_id {
-        Some(id) => id,
+fn get_bearer_token(req: &HttpRequest) -> Result<&str, HttpResponse> {
+    let header = match req.headers().get(header::AUTHORIZATION) {
+        Some(h) => h,
         None => {
             return Err(
-                HttpResponse::Unauthorized().body("Dealer ID not provided in X-User-Id Header")
+                HttpResponse::Unauthorized().body("No token provided in Authorization Header")
             );
         }
     };
-    let dealer_id = match dealer_id.to_str() {
-        Ok(d) => d,
-        Err(x) => {
-            return Err(HttpResponse::BadRequest().body(x.to_string()));
-        }
-    };
 
-    let dealer_pw = match dealer_pw {
-        None => {
-            return Err(HttpResponse::Unauthorized()
-                .body("Dealer password not provided in X-Dealer-Pw Header"));
-        }
-        Some(x) => x,
-    };
-    let dealer_pw = match dealer_pw.to_str() {
-        Ok(d) => d,
+    let header = match header.to_str() {
+        Ok(h) => h,
         Err(x) => {
             return Err(HttpResponse::BadRequest().body(x.to_string()));
         }
     };
 
-    let _id_dealer = ObjectId::parse_str(dealer_id);
-    let _id_dealer = match _id_dealer {
-        Ok(id) => id,
-        Err(_) => {
-            return Err(HttpResponse::BadRequest().body("Dealer ID is not valid utf-8"));
-        }
-    };
+    match header.strip_prefix("Bearer ") {
+        Some(token) if !token.trim().is_empty() => Ok(token.trim()),
+        _ => Err(HttpResponse::Unauthorized().body("Authorization Header is not a Bearer token")),
+    }
+}
+
+async fn get_session_user(req: &HttpRequest) -> Result<User, HttpResponse> {
+    let token = get_bearer_token(req)?;
 
-    let user = User::get(_id_dealer, ACTIVE_USERS).await;
-    let user = match user {
-        Ok(Some(d)) => d,
+    let session = Session::get_valid(token, SESSIONS).await;
+    let session = match session {
+        Ok(Some(s)) => s,
         Err(e) => {
             return Err(HttpResponse::InternalServerError().body(e.to_string()));
         }
-        _ => return Err(HttpResponse::NotFound().body("Dealer id not found")),
+        Ok(None) => {
+            return Err(HttpResponse::Unauthorized().body("Session is invalid or expired"));
+        }
     };
 
-    let dealer = match user {
-        User::Player(_) => {
-            return Err(HttpResponse::Unauthorized().body("User Id does not refer to a dealer"));
+    let user = User::get(session.user_id, ACTIVE_USERS).await;
+    match user {
+        Ok(Some(u)) => Ok(u),
+        Err(e) => Err(HttpResponse::InternalServerError().body(e.to_string())),
+        Ok(None) => Err(HttpResponse::NotFound().body("User of session not found")),
+    }
+}
+
+async fn is_user_authenticated_player(req: &HttpRequest) -> Result<Player, HttpResponse> {
+    match get_session_user(req).await? {
+        User::Player(p) => Ok(p),
+        User::Dealer(_) => {
+            Err(HttpResponse::Unauthorized().body("Session does not refer to a player"))
         }
-        User::Dealer(d) => d,
-    };
+    }
+}
 
-    let is_authorized = dealer.is_authenticated(dealer_pw).await;
-    let is_authorized = match is_authorized {
-        Ok(t) => t,
-        Err(_) => {
-            return Err(HttpResponse::BadRequest().body("Password could not be authenticated"));
+async fn is_user_authenticated_dealer(req: &HttpRequest) -> Result<Dealer, HttpResponse> {
+    match get_session_user(req).await? {
+        User::Player(_) => {
+            Err(HttpResponse::Unauthorized().body("Session does not refer to a dealer"))
         }
-    };
+        User::Dealer(d) if d.disabled => {
+            Err(HttpResponse::Forbidden().body("Dealer account is disabled"))
+        }
+        User::Dealer(d) => Ok(d),
+    }
+}
+
+async fn is_user_authorized(
+    req: &HttpRequest,
+    permission: Permission,
+) -> Result<Dealer, HttpResponse> {
+    let dealer = is_user_authenticated_dealer(req).await?;
+
+    if !dealer.role.has_permission(permission) {
+        return Err(HttpResponse::Forbidden().body(format!(
+            "Role {} is missing permission {:?}",
+            dealer.role, permission
+        )));
+    }
+
+    Ok(dealer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn forwarded_header_ignored_from_untrusted_peer() {
+        let client = forwarded_client(ip("10.0.0.5"), Some("1.2.3.4"), &[]);
+        assert_eq!(client, ip("10.0.0.5"));
+    }
+
+    #[test]
+    fn forwarded_header_used_from_trusted_proxy() {
+        let proxy = ip("10.0.0.1");
+        let client = forwarded_client(proxy, Some("1.2.3.4"), &[proxy]);
+        assert_eq!(client, ip("1.2.3.4"));
+    }
+
+    #[test]
+    fn spoofed_entries_left_of_the_client_are_ignored() {
+        let proxy = ip("10.0.0.1");
+        let client = forwarded_client(proxy, Some("6.6.6.6, 1.2.3.4"), &[proxy]);
+        assert_eq!(client, ip("1.2.3.4"));
+    }
 
-    if is_authorized {
-        return Ok(());
+    #[test]
+    fn chained_trusted_proxies_are_skipped() {
+        let trusted = [ip("10.0.0.1"), ip("10.0.0.2")];
+        let client = forwarded_client(trusted[0], Some("1.2.3.4, 10.0.0.2"), &trusted);
+        assert_eq!(client, ip("1.2.3.4"));
     }
 
-    Err(HttpResponse::Unauthorized().body("User is not authorized"))
+    #[test]
+    fn trusted_proxy_without_header_is_the_client() {
+        let proxy = ip("10.0.0.1");
+        assert_eq!(forwarded_client(proxy, None, &[proxy]), proxy);
+    }
 }
@@ -0,0 +1,80 @@
+use crate::data_source::gameday::Gameday;
+use crate::qr;
+use std::io::Error;
+
+/// Renders a print-ready A4 html sheet of cut-out cards, one card per pin.
+/// With `link` the QR code encodes the registration deep link instead of the bare pin.
+pub fn render(gameday: &Gameday, pins: &[String], link: bool) -> Result<String, Error> {
+    let name = escape(&gameday.name);
+
+    let mut cards = String::new();
+    for pin in pins {
+        let content = if link {
+            qr::registration_link(pin)?
+        } else {
+            pin.clone()
+        };
+
+        cards.push_str(&format!(
+            r#"<div class="card">
+  <div class="gameday">{name}</div>
+  <div class="qr">{qr}</div>
+  <div class="pin">{pin}</div>
+  <div class="credits">{credits} Credits</div>
+</div>
+"#,
+            name = name,
+            qr = inline_svg(&qr::render_svg(&content)?),
+            pin = escape(pin),
+            credits = gameday.initial_player_credits,
+        ));
+    }
+
+    Ok(format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>{name} - Pins</title>
+<style>
+  @page {{ size: A4; margin: 10mm; }}
+  body {{ margin: 0; font-family: sans-serif; }}
+  .sheet {{ display: flex; flex-wrap: wrap; }}
+  .card {{
+    box-sizing: border-box;
+    width: 63mm;
+    height: 88mm;
+    padding: 4mm;
+    border: 1px dashed #888;
+    text-align: center;
+    page-break-inside: avoid;
+    break-inside: avoid;
+  }}
+  .gameday {{ font-size: 12pt; font-weight: bold; }}
+  .qr svg {{ width: 45mm; height: 45mm; margin: 3mm 0; }}
+  .pin {{ font-family: monospace; font-size: 20pt; letter-spacing: 2pt; }}
+  .credits {{ font-size: 10pt; margin-top: 2mm; }}
+</style>
+</head>
+<body>
+<div class="sheet">
+{cards}</div>
+</body>
+</html>
+"#,
+        name = name,
+        cards = cards,
+    ))
+}
+
+/// Drops the xml declaration, it is not needed when the svg is part of an html document.
+fn inline_svg(svg: &str) -> &str {
+    &svg[svg.find("<svg").unwrap_or(0)..]
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,107 @@
+use qrcode::render::svg;
+use qrcode::{Color, QrCode};
+use serde::Deserialize;
+use std::env;
+use std::io::{Error, ErrorKind};
+
+const PNG_MODULE_SIZE: usize = 8;
+const QUIET_ZONE_MODULES: usize = 4;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum QrFormat {
+    Svg,
+    Png,
+}
+
+impl QrFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            QrFormat::Svg => "image/svg+xml",
+            QrFormat::Png => "image/png",
+        }
+    }
+}
+
+pub fn render(data: &str, format: QrFormat) -> Result<Vec<u8>, Error> {
+    match format {
+        QrFormat::Svg => render_svg(data).map(String::into_bytes),
+        QrFormat::Png => render_png(data),
+    }
+}
+
+/// Renders the data as a data url, so it can be embedded into json or html directly.
+pub fn render_data_url(data: &str, format: QrFormat) -> Result<String, Error> {
+    use base64::Engine;
+
+    let bytes = render(data, format)?;
+    let encoded = base64::engine::general_purpose::STANDARD.encode(bytes);
+
+    Ok(format!("data:{};base64,{}", format.content_type(), encoded))
+}
+
+pub fn render_svg(data: &str) -> Result<String, Error> {
+    let code = encode(data)?;
+
+    Ok(code
+        .render::<svg::Color>()
+        .min_dimensions(200, 200)
+        .quiet_zone(true)
+        .build())
+}
+
+pub fn render_png(data: &str) -> Result<Vec<u8>, Error> {
+    let code = encode(data)?;
+    let width = code.width();
+    let colors = code.to_colors();
+
+    let size = (width + 2 * QUIET_ZONE_MODULES) * PNG_MODULE_SIZE;
+    let mut pixels = vec![255u8; size * size];
+
+    for (i, color) in colors.iter().enumerate() {
+        if *color != Color::Dark {
+            continue;
+        }
+
+        let x0 = (i % width + QUIET_ZONE_MODULES) * PNG_MODULE_SIZE;
+        let y0 = (i / width + QUIET_ZONE_MODULES) * PNG_MODULE_SIZE;
+
+        for y in y0..y0 + PNG_MODULE_SIZE {
+            pixels[y * size + x0..y * size + x0 + PNG_MODULE_SIZE].fill(0);
+        }
+    }
+
+    let mut out = vec![];
+    let mut encoder = png::Encoder::new(&mut out, size as u32, size as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut writer = encoder.write_header().map_err(Error::other)?;
+    writer.write_image_data(&pixels).map_err(Error::other)?;
+    writer.finish().map_err(Error::other)?;
+
+    Ok(out)
+}
+
+/// Base url of the registration page, configured through REGISTRATION_URL.
+pub fn registration_base() -> Result<String, Error> {
+    env::var("REGISTRATION_URL").map_err(|_| {
+        Error::new(
+            ErrorKind::NotFound,
+            "Registration url not set up - Env var should be: REGISTRATION_URL",
+        )
+    })
+}
+
+/// Deep link a guest can open to land on the registration page with the pin filled in.
+pub fn registration_link(pin: &str) -> Result<String, Error> {
+    let base = registration_base()?;
+
+    let separator = if base.contains('?') { '&' } else { '?' };
+
+    Ok(format!("{}{}pin={}", base, separator, pin))
+}
+
+fn encode(data: &str) -> Result<QrCode, Error> {
+    QrCode::new(data.as_bytes()).map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))
+}